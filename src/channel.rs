@@ -1,103 +1,881 @@
+use std::collections::VecDeque;
+use std::future::Future;
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
-use std::task::{Context, Poll};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 
-use futures::{
-    channel::mpsc::{channel as mpsc_channel, Receiver, Sender},
-    Stream,
-};
+use custom_error::custom_error;
+use futures::Stream;
+use serde::Serialize;
 
 use crate::chunk::Chunk;
+use crate::fixers::SourceSplicer;
+use crate::tracks::{tracks_compatible, TrackEntry};
+
+custom_error!{pub ChannelError
+    Busy = "channel already has an active source",
+    Superseded = "another source took over the channel",
+    Kicked = "an administrator disconnected the source"
+}
+
+/// How much recent media a channel keeps around for listeners to start from.
+/// The buffer holds whole keyframe-to-keyframe groups of clusters, and the
+/// oldest group is discarded when either limit is exceeded. A zero limit
+/// doesn't apply; with both zero, nothing is buffered beyond the GOP cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BufferLimits {
+    pub duration: Duration,
+    pub bytes: usize,
+}
+
+/// What to do when a source connects to a channel that already has one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransmitterPolicy {
+    /// Refuse the new source
+    Reject,
+    /// Switch to the new source; the old one is told it was superseded
+    Takeover,
+    /// Keep the new source waiting, and switch to it when the current one disconnects
+    Standby,
+}
+
+impl FromStr for TransmitterPolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        match name {
+            "reject" => Ok(TransmitterPolicy::Reject),
+            "takeover" => Ok(TransmitterPolicy::Takeover),
+            "standby" => Ok(TransmitterPolicy::Standby),
+            _ => Err(format!("unknown transmitter policy {:?} (expected reject, takeover or standby)", name))
+        }
+    }
+}
+
+/// What to do with a listener whose queue is full because it isn't keeping up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SlowListenerPolicy {
+    /// Drop the listener
+    Disconnect,
+    /// Stop queueing clusters until the listener has drained its queue, then resume at the next keyframe
+    SkipToKeyframe,
+    /// Drop clusters until the next keyframe, which is queued even if the queue is full.
+    /// A listener that falls twice as far behind as the queue limit is still dropped.
+    DropNonKeyframes,
+}
+
+impl FromStr for SlowListenerPolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        match name {
+            "disconnect" => Ok(SlowListenerPolicy::Disconnect),
+            "skip-to-keyframe" => Ok(SlowListenerPolicy::SkipToKeyframe),
+            "drop-non-keyframes" => Ok(SlowListenerPolicy::DropNonKeyframes),
+            _ => Err(format!("unknown slow listener policy {:?} (expected disconnect, skip-to-keyframe or drop-non-keyframes)", name))
+        }
+    }
+}
+
+/// How much a listener may have queued before it counts as falling behind
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueueLimit {
+    Clusters(usize),
+    Bytes(usize),
+}
+
+impl QueueLimit {
+    fn scaled(self, factor: usize) -> QueueLimit {
+        match self {
+            QueueLimit::Clusters(count) => QueueLimit::Clusters(count.saturating_mul(factor)),
+            QueueLimit::Bytes(bytes) => QueueLimit::Bytes(bytes.saturating_mul(factor)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ChannelSettings {
+    pub buffer: BufferLimits,
+    /// Regardless of `buffer`, keep every cluster since the latest keyframe (up to
+    /// this many bytes) so new listeners can start right away; 0 disables this.
+    pub gop_cache_bytes: usize,
+    pub listener_queue: QueueLimit,
+    pub slow_listener_policy: SlowListenerPolicy,
+    pub transmitter_policy: TransmitterPolicy,
+    /// How long the active source may go without sending anything before a backup source takes over
+    pub failover_timeout: Duration,
+}
+
+pub const DEFAULT_GOP_CACHE_BYTES: usize = 16 * 1024 * 1024;
+pub const DEFAULT_LISTENER_QUEUE: QueueLimit = QueueLimit::Clusters(5);
+pub const DEFAULT_FAILOVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl Default for ChannelSettings {
+    fn default() -> Self {
+        ChannelSettings {
+            buffer: BufferLimits::default(),
+            gop_cache_bytes: DEFAULT_GOP_CACHE_BYTES,
+            listener_queue: DEFAULT_LISTENER_QUEUE,
+            slow_listener_policy: SlowListenerPolicy::Disconnect,
+            transmitter_policy: TransmitterPolicy::Takeover,
+            failover_timeout: DEFAULT_FAILOVER_TIMEOUT,
+        }
+    }
+}
+
+/// How much media time the bitrate in a `ChannelStatus` is averaged over
+pub const BITRATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// A snapshot of what a channel is doing, for monitoring
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ChannelStatus {
+    pub name: String,
+    /// whether a source (possibly a backup that took over) is being relayed
+    pub transmitter_connected: bool,
+    /// whether a backup source is connected, whether or not it has taken over
+    pub backup_connected: bool,
+    pub slate_active: bool,
+    pub listeners: usize,
+    /// codec IDs of the tracks in the current header, e.g. `V_VP8`
+    pub codecs: Vec<String>,
+    /// bits per second of media relayed, over the last `BITRATE_WINDOW` of clusters
+    pub bitrate: Option<u64>,
+    /// how long the current source has been relayed
+    pub uptime_seconds: Option<u64>,
+    /// start of the latest cluster relayed, in the channel's (continuous) timeline
+    pub last_timecode_ms: Option<u64>,
+}
+
+/// Upper bounds of the buckets in `ChannelCounters::cluster_durations`
+pub const CLUSTER_DURATION_BUCKETS: [Duration; 6] = [
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+];
+
+/// Running totals since a channel was opened, for monitoring
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChannelCounters {
+    /// bytes of media accepted from sources, including standby & backup ones
+    pub bytes_in: u64,
+    /// bytes of media queued for listeners
+    pub bytes_out: u64,
+    /// listeners disconnected by the slow listener policy
+    pub listeners_dropped: u64,
+    /// how many relayed clusters were no longer than each of `CLUSTER_DURATION_BUCKETS`
+    pub cluster_durations: [u64; CLUSTER_DURATION_BUCKETS.len()],
+    pub clusters: u64,
+    pub cluster_duration_sum: Duration,
+}
+
+impl ChannelCounters {
+    fn observe_cluster(&mut self, duration: Duration) {
+        for (count, bound) in self.cluster_durations.iter_mut().zip(CLUSTER_DURATION_BUCKETS.iter()) {
+            if duration <= *bound {
+                *count += 1;
+            }
+        }
+        self.clusters += 1;
+        self.cluster_duration_sum += duration;
+    }
+}
 
 /// A collection of listeners to a stream of WebM chunks.
-/// Sending a chunk may fail due to a client being disconnected,
-/// or simply failing to keep up with the stream buffer. In either
-/// case, there's nothing practical the server can do to recover,
-/// so the failing client is just dropped from the listener list.
+/// Listeners that disconnect are dropped from the listener list;
+/// listeners that fail to keep up with the stream are handled
+/// according to the channel's `SlowListenerPolicy`.
 pub struct Channel {
     pub name: String,
+    settings: ChannelSettings,
     header_chunk: Option<Chunk>,
-    listeners: Vec<Sender<Chunk>>,
+    /// starts each source at a keyframe, keeping timecodes monotonic so buffered clusters line up
+    splicer: SourceSplicer,
+    /// recent clusters, oldest first; always begins with a keyframe cluster if nonempty
+    buffer: VecDeque<Chunk>,
+    buffered_bytes: usize,
+    listeners: Vec<Weak<Mutex<ListenerQueue>>>,
+    /// the source currently being relayed, if any
+    active_transmitter: Option<u64>,
+    /// sources waiting to take over, oldest first
+    standby: VecDeque<StandbySource>,
+    /// a designated backup source, kept warm and relayed only while no other source is
+    backup: Option<StandbySource>,
+    /// when the active source last sent anything
+    last_publish: Instant,
+    /// when the active source started being relayed
+    activated_at: Instant,
+    /// start, end & size of the clusters relayed within the last `BITRATE_WINDOW` of media time
+    recent_clusters: VecDeque<(u128, u128, usize)>,
+    counters: ChannelCounters,
+    next_transmitter_id: u64,
+    /// sources disconnected by `kick_transmitter`, until they next try to send
+    kicked: Vec<u64>,
+    /// wakers of `Kicked` futures, by transmitter
+    kick_wakers: Vec<(u64, Waker)>,
+    /// wakers of `Idle` futures, woken once no source is left
+    idle_wakers: Vec<Waker>,
+    /// tracks of the latest header relayed, remembered after its source leaves
+    tracks: Option<Vec<TrackEntry>>,
+    /// TimecodeScale of that header, which the slate must share as well
+    timecode_scale: Option<u64>,
+    /// header of the fallback stream given to `send_slate`
+    slate_header: Option<Chunk>,
+    /// whether the slate is being relayed
+    slate_active: bool,
+    /// whether the slate's incompatibility with the current tracks was already logged
+    slate_rejected: bool,
+}
+
+struct StandbySource {
+    id: u64,
+    /// the latest header sent by the source, to publish once it becomes active
+    header: Option<Chunk>,
 }
 
 pub type Handle = Arc<Mutex<Channel>>;
 
 impl Channel {
     pub fn new(name: String) -> Handle {
+        Channel::with_settings(name, ChannelSettings::default())
+    }
+
+    pub fn with_settings(name: String, settings: ChannelSettings) -> Handle {
         info!("Opening Channel {}", name);
         Arc::new(Mutex::new(Channel {
             name,
+            settings,
             header_chunk: None,
+            splicer: SourceSplicer::new(),
+            buffer: VecDeque::new(),
+            buffered_bytes: 0,
             listeners: Vec::new(),
+            active_transmitter: None,
+            standby: VecDeque::new(),
+            backup: None,
+            last_publish: Instant::now(),
+            activated_at: Instant::now(),
+            recent_clusters: VecDeque::new(),
+            counters: ChannelCounters::default(),
+            next_transmitter_id: 0,
+            kicked: Vec::new(),
+            kick_wakers: Vec::new(),
+            idle_wakers: Vec::new(),
+            tracks: None,
+            timecode_scale: None,
+            slate_header: None,
+            slate_active: false,
+            slate_rejected: false,
         }))
     }
+
+    /// Apply new settings; the buffer and listener queues adjust as further clusters arrive
+    pub fn update_settings(&mut self, settings: ChannelSettings) {
+        self.settings = settings;
+    }
+
+    pub fn status(&self) -> ChannelStatus {
+        let relaying = self.active_transmitter.is_some() || self.slate_active;
+        let codecs = match (&self.header_chunk, &self.tracks) {
+            (Some(_), Some(tracks)) => tracks.iter().map(|track| track.codec_id.clone()).collect(),
+            _ => Vec::new(),
+        };
+        ChannelStatus {
+            name: self.name.clone(),
+            transmitter_connected: self.active_transmitter.is_some(),
+            backup_connected: self.backup.is_some(),
+            slate_active: self.slate_active,
+            listeners: self.listeners.iter().filter(|queue| queue.strong_count() > 0).count(),
+            codecs,
+            bitrate: if relaying { self.bitrate() } else { None },
+            uptime_seconds: self.active_transmitter.map(|_| self.activated_at.elapsed().as_secs()),
+            last_timecode_ms: self.recent_clusters.back().map(|&(start, _, _)| (start / 1_000_000) as u64),
+        }
+    }
+
+    pub fn counters(&self) -> &ChannelCounters {
+        &self.counters
+    }
+
+    fn bitrate(&self) -> Option<u64> {
+        let start = self.recent_clusters.front()?.0;
+        let end = self.recent_clusters.back()?.1;
+        if end <= start {
+            return None;
+        }
+        let bytes: usize = self.recent_clusters.iter().map(|&(_, _, size)| size).sum();
+        Some((bytes as u128 * 8 * 1_000_000_000 / (end - start)) as u64)
+    }
+
+    /// A future that resolves once the channel has no source relaying (or is closed),
+    /// so a slate only needs to be fed to it while that's so
+    pub fn idle(channel: &Handle) -> Idle {
+        Idle {
+            channel: Arc::downgrade(channel),
+        }
+    }
+
+    /// Whether a source is being relayed
+    pub fn has_source(&self) -> bool {
+        self.active_transmitter.is_some()
+    }
+
+    /// Relay a chunk of a looping fallback ("slate") stream while no source is connected,
+    /// provided its codecs and TimecodeScale match those of the last source, so listeners can keep playing.
+    /// The slate's timecodes should be monotonic across loops.
+    pub fn send_slate(&mut self, chunk: Chunk) {
+        if let Chunk::Headers { .. } = chunk {
+            self.slate_header = Some(chunk);
+            return;
+        }
+        if self.active_transmitter.is_some() {
+            return;
+        }
+
+        if !self.slate_active {
+            let header = match self.slate_header {
+                Some(ref header) => header.clone(),
+                None => return,
+            };
+            let compatible = match (&self.tracks, header.tracks()) {
+                (None, _) => true,
+                (Some(tracks), Some(slate_tracks)) => {
+                    tracks_compatible(tracks, &slate_tracks) && self.timecode_scale == header.timecode_scale()
+                },
+                (Some(_), None) => false,
+            };
+            if !compatible {
+                if !self.slate_rejected {
+                    warn!("Slate doesn't match the codecs or TimecodeScale of the last source on Channel {}; not relaying it", self.name);
+                    self.slate_rejected = true;
+                }
+                return;
+            }
+
+            info!("Relaying the slate on Channel {}", self.name);
+            self.slate_active = true;
+            self.splicer.discontinuity();
+            self.publish(header);
+        }
+        self.publish(chunk);
+    }
+
+    fn activate(&mut self, id: u64, header: Option<Chunk>) {
+        self.active_transmitter = Some(id);
+        self.slate_active = false;
+        self.last_publish = Instant::now();
+        self.activated_at = Instant::now();
+        self.splicer.discontinuity();
+        if let Some(header) = header {
+            self.publish(header);
+        }
+    }
+
+    /// Disconnect the active source, as if it had left, so a standby or backup source can take
+    /// over; its `Transmitter` fails from then on. Returns whether there was a source to disconnect.
+    pub fn kick_transmitter(&mut self) -> bool {
+        let id = match self.active_transmitter {
+            Some(id) => id,
+            None => return false,
+        };
+        info!("Disconnecting the source on Channel {}", self.name);
+        if self.backup_id() == Some(id) {
+            self.backup = None;
+        }
+        self.kicked.push(id);
+        if let Some(index) = self.kick_wakers.iter().position(|&(waiting, _)| waiting == id) {
+            self.kick_wakers.swap_remove(index).1.wake();
+        }
+        self.source_left();
+        true
+    }
+
+    /// Disconnect every listener, returning how many there were
+    pub fn kick_listeners(&mut self) -> usize {
+        let mut count = 0;
+        for queue in self.listeners.drain(..).filter_map(|queue| queue.upgrade()) {
+            queue.lock().expect("Locking listener queue").close();
+            count += 1;
+        }
+        info!("Disconnected {} listeners from Channel {}", count, self.name);
+        count
+    }
+
+    /// The active source is gone; let a waiting source take over, if there is one
+    fn source_left(&mut self) {
+        // clean up the header chunk so subsequent clients
+        // don't get a potentially incorrect initialization segment
+        self.active_transmitter = None;
+        self.header_chunk = None;
+        self.clear_buffer();
+
+        if let Some(standby) = self.standby.pop_front() {
+            info!("Standby source taking over Channel {}", self.name);
+            self.activate(standby.id, standby.header);
+        } else if let Some(StandbySource { id, ref header }) = self.backup {
+            info!("Failing over to the backup source on Channel {}", self.name);
+            let header = header.clone();
+            self.activate(id, header);
+        }
+        if self.active_transmitter.is_none() {
+            for waker in self.idle_wakers.drain(..) {
+                waker.wake();
+            }
+        }
+    }
+
+    fn backup_id(&self) -> Option<u64> {
+        self.backup.as_ref().map(|backup| backup.id)
+    }
+
+    /// Whether a backup source should take over
+    fn active_source_failed(&self) -> bool {
+        match self.active_transmitter {
+            None => true,
+            Some(_) => self.last_publish.elapsed() >= self.settings.failover_timeout,
+        }
+    }
+
+    /// Relay a chunk from the active source to the buffer and listeners
+    fn publish(&mut self, chunk: Chunk) {
+        self.last_publish = Instant::now();
+        let chunk = match self.splicer.process(chunk) {
+            Some(chunk) => chunk,
+            None => return,
+        };
+        match chunk {
+            Chunk::Headers { ref bytes } => {
+                let unchanged = matches!(self.header_chunk, Some(Chunk::Headers { bytes: ref old }) if old == bytes);
+                if !unchanged {
+                    // buffered clusters may not be decodable with a different header
+                    self.clear_buffer();
+                    self.tracks = chunk.tracks();
+                    self.timecode_scale = chunk.timecode_scale();
+                    self.slate_rejected = false;
+                }
+                self.header_chunk = Some(chunk.clone());
+            },
+            Chunk::Cluster(ref head, _) => {
+                let duration = head.end_nanos().saturating_sub(head.start_nanos());
+                self.counters.observe_cluster(Duration::from_nanos(duration as u64));
+                self.recent_clusters.push_back((head.start_nanos(), head.end_nanos(), chunk.byte_size()));
+                let window_start = head.end_nanos().saturating_sub(BITRATE_WINDOW.as_nanos());
+                while matches!(self.recent_clusters.front(), Some(&(_, end, _)) if end <= window_start) {
+                    self.recent_clusters.pop_front();
+                }
+                self.buffer_cluster(&chunk);
+            },
+            _ => {}
+        }
+
+        let Channel { ref name, ref settings, ref mut listeners, ref mut counters, .. } = *self;
+        let size = chunk.byte_size() as u64;
+        listeners.retain(|queue| match queue.upgrade() {
+            Some(queue) => match queue.lock().expect("Locking listener queue").offer(&chunk, settings, name) {
+                Delivery::Queued => {
+                    counters.bytes_out += size;
+                    true
+                },
+                Delivery::Skipped => true,
+                Delivery::Dropped => {
+                    counters.listeners_dropped += 1;
+                    false
+                },
+            },
+            None => false
+        });
+    }
+
+    fn clear_buffer(&mut self) {
+        self.buffer.clear();
+        self.buffered_bytes = 0;
+    }
+
+    fn buffer_cluster(&mut self, chunk: &Chunk) {
+        if self.buffer.is_empty() && !chunk.is_keyframe() {
+            // nobody could start decoding here, so don't bother keeping it
+            return;
+        }
+        self.buffered_bytes += chunk.byte_size();
+        self.buffer.push_back(chunk.clone());
+
+        while self.buffer_exceeds_limits() {
+            // drop the oldest group of clusters, up to the next keyframe
+            match self.buffer.iter().skip(1).position(Chunk::is_keyframe) {
+                Some(index) => {
+                    for dropped in self.buffer.drain(..=index) {
+                        self.buffered_bytes -= dropped.byte_size();
+                    }
+                },
+                None => {
+                    // only the current group is left; keep it as the GOP cache if it fits
+                    if self.buffered_bytes > self.settings.gop_cache_bytes {
+                        self.clear_buffer();
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    fn buffer_exceeds_limits(&self) -> bool {
+        let limits = self.settings.buffer;
+        if limits.bytes == 0 && limits.duration == Duration::ZERO {
+            return !self.buffer.is_empty();
+        }
+        let duration = match (self.buffer.front(), self.buffer.back()) {
+            (Some(Chunk::Cluster(first, _)), Some(Chunk::Cluster(last, _))) => {
+                last.end_nanos().saturating_sub(first.start_nanos())
+            },
+            _ => 0
+        };
+        (limits.bytes > 0 && self.buffered_bytes > limits.bytes)
+            || (limits.duration > Duration::ZERO && duration > limits.duration.as_nanos())
+    }
+
+    /// The chunks a new listener should start with: the header, then buffered clusters
+    /// starting from the latest keyframe at least `offset` behind the live edge
+    /// (or the oldest one available, if the buffer doesn't reach back that far).
+    fn starting_chunks(&self, offset: Duration) -> Vec<Chunk> {
+        let header = match self.header_chunk {
+            Some(ref header) => header.clone(),
+            None => return Vec::new(),
+        };
+
+        let live_edge = match self.buffer.back() {
+            Some(Chunk::Cluster(last, _)) => last.end_nanos(),
+            _ => return vec![header],
+        };
+        let target = live_edge.saturating_sub(offset.as_nanos());
+        let start_index = self.buffer.iter().rposition(|chunk| match chunk {
+            Chunk::Cluster(head, _) => head.keyframe && head.start_nanos() <= target,
+            _ => false
+        }).unwrap_or(0);
+
+        let mut chunks = vec![header];
+        chunks.extend(self.buffer.iter().skip(start_index).cloned());
+        chunks
+    }
 }
 
 impl Drop for Channel {
     fn drop(&mut self) {
         info!("Closing Channel {}", self.name);
+        for waker in self.idle_wakers.drain(..) {
+            waker.wake();
+        }
     }
 }
 
 pub struct Transmitter {
     channel: Handle,
+    id: u64,
 }
 
 impl Transmitter {
-    pub fn new(channel_arc: Handle) -> Self {
-        Transmitter {
+    /// Connect a source to the channel, subject to its `TransmitterPolicy`
+    /// if another source is already connected
+    pub fn new(channel_arc: Handle) -> Result<Self, ChannelError> {
+        let id = {
+            let mut channel = channel_arc.lock().expect("Locking channel");
+            let id = channel.next_transmitter_id;
+            channel.next_transmitter_id += 1;
+
+            // a backup source gives way to any primary one
+            let backup_id = channel.backup_id();
+            let active_primary = channel.active_transmitter.filter(|&active| Some(active) != backup_id);
+            match (active_primary, channel.settings.transmitter_policy) {
+                (None, _) => channel.activate(id, None),
+                (Some(_), TransmitterPolicy::Reject) => {
+                    warn!("Rejecting a second source on Channel {}", channel.name);
+                    return Err(ChannelError::Busy);
+                },
+                (Some(_), TransmitterPolicy::Takeover) => {
+                    info!("New source taking over Channel {}", channel.name);
+                    channel.activate(id, None);
+                },
+                (Some(_), TransmitterPolicy::Standby) => {
+                    info!("Holding a second source on Channel {} in standby", channel.name);
+                    channel.standby.push_back(StandbySource { id, header: None });
+                }
+            }
+            id
+        };
+
+        Ok(Transmitter {
+            channel: channel_arc,
+            id,
+        })
+    }
+
+    /// Connect a backup source to the channel; it's only relayed while no other source is
+    /// connected, or after the active source has sent nothing for the channel's `failover_timeout`.
+    /// Only one backup source can be connected at a time.
+    pub fn backup(channel_arc: Handle) -> Result<Self, ChannelError> {
+        let id = {
+            let mut channel = channel_arc.lock().expect("Locking channel");
+            if channel.backup.is_some() {
+                warn!("Rejecting a second backup source on Channel {}", channel.name);
+                return Err(ChannelError::Busy);
+            }
+            let id = channel.next_transmitter_id;
+            channel.next_transmitter_id += 1;
+            info!("Backup source connected on Channel {}", channel.name);
+            channel.backup = Some(StandbySource { id, header: None });
+            id
+        };
+
+        Ok(Transmitter {
             channel: channel_arc,
+            id,
+        })
+    }
+
+    /// A future that resolves if `Channel::kick_transmitter` disconnects this source
+    pub fn kicked(&self) -> Kicked {
+        Kicked {
+            channel: self.channel.clone(),
+            id: self.id,
         }
     }
 
-    pub fn send(&self, chunk: Chunk) {
+    /// Relay a chunk to the channel's listeners; a standby or backup source's chunks are held back.
+    /// Returns whether the chunk was relayed, and fails once another source has taken over the channel.
+    pub fn send(&self, chunk: Chunk) -> Result<bool, ChannelError> {
         let mut channel = self.channel.lock().expect("Locking channel");
+        if channel.kicked.contains(&self.id) {
+            return Err(ChannelError::Kicked);
+        }
+        channel.counters.bytes_in += chunk.byte_size() as u64;
 
-        if let Chunk::Headers { .. } = chunk {
-            channel.header_chunk = Some(chunk.clone());
+        if channel.active_transmitter != Some(self.id) && channel.backup_id() == Some(self.id) {
+            let is_header = matches!(chunk, Chunk::Headers { .. });
+            let backup = channel.backup.as_mut().expect("Backup source");
+            // a header being sent now is published as usual once the backup is active
+            let header = if is_header {
+                backup.header = Some(chunk.clone());
+                None
+            } else {
+                backup.header.clone()
+            };
+            if !channel.active_source_failed() {
+                return Ok(false);
+            }
+            info!("Failing over to the backup source on Channel {}", channel.name);
+            channel.activate(self.id, header);
         }
 
-        channel
-            .listeners
-            .retain_mut(|listener| listener.start_send(chunk.clone()).is_ok());
+        if channel.active_transmitter != Some(self.id) {
+            let standby = channel.standby.iter_mut().find(|standby| standby.id == self.id);
+            return match standby {
+                Some(standby) => {
+                    if let Chunk::Headers { .. } = chunk {
+                        standby.header = Some(chunk);
+                    }
+                    Ok(false)
+                },
+                None => Err(ChannelError::Superseded)
+            };
+        }
+
+        channel.publish(chunk);
+        Ok(true)
     }
 }
 
 impl Drop for Transmitter {
     fn drop(&mut self) {
         if let Ok(mut channel) = self.channel.lock() {
-            // when disconnecting, clean up the header chunk so subsequent
-            // clients don't get a potentially incorrect initialization segment
-            channel.header_chunk = None;
+            channel.kicked.retain(|&id| id != self.id);
+            channel.kick_wakers.retain(|&(id, _)| id != self.id);
+            if channel.backup_id() == Some(self.id) {
+                channel.backup = None;
+            }
+            if channel.active_transmitter != Some(self.id) {
+                channel.standby.retain(|standby| standby.id != self.id);
+                return;
+            }
+            channel.source_left();
         }
     }
 }
 
+/// Resolves once `Channel::kick_transmitter` disconnects a particular source
+pub struct Kicked {
+    channel: Handle,
+    id: u64,
+}
+
+impl Future for Kicked {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let id = self.id;
+        let mut channel = self.channel.lock().expect("Locking channel");
+        if channel.kicked.contains(&id) {
+            return Poll::Ready(());
+        }
+        match channel.kick_wakers.iter_mut().find(|(waiting, _)| *waiting == id) {
+            Some((_, waker)) => waker.clone_from(cx.waker()),
+            None => channel.kick_wakers.push((id, cx.waker().clone())),
+        }
+        Poll::Pending
+    }
+}
+
+/// Resolves once a channel has no source, or has closed; see `Channel::idle`
+pub struct Idle {
+    channel: Weak<Mutex<Channel>>,
+}
+
+impl Future for Idle {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let channel = match self.channel.upgrade() {
+            Some(channel) => channel,
+            None => return Poll::Ready(()),
+        };
+        let mut channel = channel.lock().expect("Locking channel");
+        if channel.active_transmitter.is_none() {
+            return Poll::Ready(());
+        }
+        channel.idle_wakers.retain(|waker| !waker.will_wake(cx.waker()));
+        channel.idle_wakers.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// What became of a chunk offered to a listener
+enum Delivery {
+    Queued,
+    /// not queued, as the listener is behind
+    Skipped,
+    /// the listener fell too far behind, and should be dropped
+    Dropped,
+}
+
+/// Live chunks waiting to be taken by a Listener
+#[derive(Default)]
+struct ListenerQueue {
+    chunks: VecDeque<Chunk>,
+    clusters: usize,
+    cluster_bytes: usize,
+    /// set while the listener is behind, and clusters are being dropped until a keyframe
+    skipping: bool,
+    /// set once the channel stops delivering to this listener
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+impl ListenerQueue {
+    fn is_full(&self, limit: QueueLimit, incoming_bytes: usize) -> bool {
+        match limit {
+            QueueLimit::Clusters(count) => self.clusters >= count,
+            // always allow one cluster, however big, so the listener can make progress
+            QueueLimit::Bytes(bytes) => self.clusters > 0 && self.cluster_bytes + incoming_bytes > bytes,
+        }
+    }
+
+    fn push(&mut self, chunk: &Chunk) {
+        if let Chunk::Cluster(..) = chunk {
+            self.clusters += 1;
+            self.cluster_bytes += chunk.byte_size();
+        }
+        self.chunks.push_back(chunk.clone());
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn pop(&mut self) -> Option<Chunk> {
+        let chunk = self.chunks.pop_front()?;
+        if let Chunk::Cluster(..) = chunk {
+            self.clusters -= 1;
+            self.cluster_bytes -= chunk.byte_size();
+        }
+        Some(chunk)
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Queue a chunk, applying the slow listener policy if the queue is full
+    fn offer(&mut self, chunk: &Chunk, settings: &ChannelSettings, channel_name: &str) -> Delivery {
+        if let Chunk::Cluster(..) = chunk {
+            let keyframe = chunk.is_keyframe();
+            let full = self.is_full(settings.listener_queue, chunk.byte_size());
+            if self.skipping || full {
+                let resume = match settings.slow_listener_policy {
+                    SlowListenerPolicy::Disconnect => {
+                        warn!("Disconnecting a listener that fell behind on Channel {}", channel_name);
+                        self.close();
+                        return Delivery::Dropped;
+                    },
+                    SlowListenerPolicy::SkipToKeyframe => keyframe && self.clusters == 0,
+                    SlowListenerPolicy::DropNonKeyframes => {
+                        if keyframe && self.is_full(settings.listener_queue.scaled(2), chunk.byte_size()) {
+                            warn!("Disconnecting a listener that fell too far behind on Channel {}", channel_name);
+                            self.close();
+                            return Delivery::Dropped;
+                        }
+                        keyframe
+                    }
+                };
+                if !resume {
+                    if !self.skipping {
+                        info!("Listener fell behind on Channel {}, skipping to a later keyframe", channel_name);
+                        self.skipping = true;
+                    }
+                    return Delivery::Skipped;
+                }
+                self.skipping = false;
+            }
+        }
+        self.push(chunk);
+        Delivery::Queued
+    }
+}
+
 pub struct Listener {
     /// not used in operation, but its refcount keeps the channel alive when there's no Transmitter
     _channel: Handle,
-    receiver: Receiver<Chunk>,
+    /// chunks from before the listener joined, to be sent before any live ones
+    backlog: VecDeque<Chunk>,
+    queue: Arc<Mutex<ListenerQueue>>,
 }
 
 impl Listener {
+    /// Listen to the channel, starting from the most recent buffered keyframe if possible
     pub fn new(channel_arc: Handle) -> Self {
-        let (mut sender, receiver) = mpsc_channel(5);
-
-        {
-            let mut channel = channel_arc.lock().expect("Locking channel");
+        Listener::with_offset(channel_arc, Duration::ZERO)
+    }
 
-            if let Some(ref chunk) = channel.header_chunk {
-                sender
-                    .start_send(chunk.clone())
-                    .expect("Queuing existing header chunk");
-            }
+    /// Listen to the channel, starting about `offset` behind the live stream
+    /// if the channel has buffered that much
+    pub fn with_offset(channel_arc: Handle, offset: Duration) -> Self {
+        let queue = Arc::new(Mutex::new(ListenerQueue::default()));
 
-            channel.listeners.push(sender);
-        }
+        let backlog = {
+            let mut channel = channel_arc.lock().expect("Locking channel");
+            channel.listeners.push(Arc::downgrade(&queue));
+            let backlog = channel.starting_chunks(offset);
+            channel.counters.bytes_out += backlog.iter().map(|chunk| chunk.byte_size() as u64).sum::<u64>();
+            backlog.into()
+        };
 
         Listener {
             _channel: channel_arc,
-            receiver: receiver,
+            backlog,
+            queue,
         }
     }
 }
@@ -106,7 +884,551 @@ impl Stream for Listener {
     type Item = Chunk;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Chunk>> {
-        let receiver = &mut self.get_mut().receiver;
-        Pin::new(receiver).poll_next(cx)
+        let listener = self.get_mut();
+        if let Some(chunk) = listener.backlog.pop_front() {
+            return Poll::Ready(Some(chunk));
+        }
+        let mut queue = listener.queue.lock().expect("Locking listener queue");
+        if let Some(chunk) = queue.pop() {
+            Poll::Ready(Some(chunk))
+        } else if queue.closed {
+            Poll::Ready(None)
+        } else {
+            queue.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::{FutureExt, StreamExt};
+
+    use crate::channel::*;
+    use crate::tests::{cluster, scaled_header, tracks_header};
+
+    fn header() -> Chunk {
+        Chunk::Headers { bytes: Bytes::from_static(b"header") }
+    }
+
+    /// start times (in seconds) of the clusters a new listener would be sent
+    fn replayed(listener: &mut Listener) -> Vec<u64> {
+        let mut starts = Vec::new();
+        while let Some(Some(chunk)) = listener.next().now_or_never() {
+            match chunk {
+                Chunk::Cluster(head, _) => starts.push(head.start / 1000),
+                Chunk::Headers { .. } => assert!(starts.is_empty(), "header must come first"),
+                _ => {}
+            }
+        }
+        starts
+    }
+
+    fn buffered_channel(limits: BufferLimits) -> (Handle, Transmitter) {
+        buffered_channel_with_settings(ChannelSettings {
+            buffer: limits,
+            gop_cache_bytes: 0,
+            ..ChannelSettings::default()
+        })
+    }
+
+    fn buffered_channel_with_settings(settings: ChannelSettings) -> (Handle, Transmitter) {
+        let channel = Channel::with_settings("test".into(), settings);
+        let transmitter = Transmitter::new(channel.clone()).unwrap();
+        transmitter.send(header()).unwrap();
+        // keyframes every 3 seconds
+        for second in 0..10 {
+            transmitter.send(cluster(second, second % 3 == 0)).unwrap();
+        }
+        (channel, transmitter)
+    }
+
+    #[test]
+    fn no_buffer_when_disabled() {
+        let (channel, _transmitter) = buffered_channel(BufferLimits::default());
+        assert_eq!(replayed(&mut Listener::new(channel)), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn latest_gop_cached_by_default() {
+        let (channel, transmitter) = buffered_channel_with_settings(ChannelSettings::default());
+        assert_eq!(replayed(&mut Listener::new(channel.clone())), vec![9]);
+        assert_eq!(replayed(&mut Listener::with_offset(channel.clone(), Duration::from_secs(600))), vec![9]);
+
+        transmitter.send(cluster(10, false)).unwrap();
+        assert_eq!(replayed(&mut Listener::new(channel.clone())), vec![9, 10]);
+
+        transmitter.send(cluster(11, false)).unwrap();
+        transmitter.send(cluster(12, true)).unwrap();
+        assert_eq!(replayed(&mut Listener::new(channel)), vec![12]);
+    }
+
+    #[test]
+    fn oversized_gop_not_cached() {
+        let (channel, transmitter) = buffered_channel_with_settings(ChannelSettings {
+            gop_cache_bytes: 250,
+            ..ChannelSettings::default()
+        });
+        assert_eq!(replayed(&mut Listener::new(channel.clone())), vec![9]);
+
+        transmitter.send(cluster(10, false)).unwrap();
+        transmitter.send(cluster(11, false)).unwrap();
+        assert_eq!(replayed(&mut Listener::new(channel)), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn join_at_latest_keyframe() {
+        let (channel, _transmitter) = buffered_channel(BufferLimits {
+            duration: Duration::from_secs(60),
+            bytes: 1_000_000,
+        });
+        assert_eq!(replayed(&mut Listener::new(channel.clone())), vec![9]);
+        assert_eq!(replayed(&mut Listener::with_offset(channel.clone(), Duration::from_secs(2))), vec![6, 7, 8, 9]);
+        assert_eq!(replayed(&mut Listener::with_offset(channel, Duration::from_secs(600))), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn buffer_limits_drop_whole_groups() {
+        let (channel, _transmitter) = buffered_channel(BufferLimits {
+            duration: Duration::from_secs(5),
+            bytes: 1_000_000,
+        });
+        assert_eq!(replayed(&mut Listener::with_offset(channel, Duration::from_secs(600))), vec![6, 7, 8, 9]);
+
+        let (channel, _transmitter) = buffered_channel(BufferLimits {
+            duration: Duration::from_secs(60),
+            bytes: 500,
+        });
+        assert_eq!(replayed(&mut Listener::with_offset(channel, Duration::from_secs(600))), vec![6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn single_buffer_limit_applies_alone() {
+        let (channel, _transmitter) = buffered_channel(BufferLimits {
+            duration: Duration::from_secs(5),
+            bytes: 0,
+        });
+        assert_eq!(replayed(&mut Listener::with_offset(channel, Duration::from_secs(600))), vec![6, 7, 8, 9]);
+
+        let (channel, _transmitter) = buffered_channel(BufferLimits {
+            duration: Duration::ZERO,
+            bytes: 500,
+        });
+        assert_eq!(replayed(&mut Listener::with_offset(channel, Duration::from_secs(600))), vec![6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn buffer_survives_restarted_source() {
+        let (channel, transmitter) = buffered_channel(BufferLimits {
+            duration: Duration::from_secs(60),
+            bytes: 1_000_000,
+        });
+        // same stream again, timecodes starting over
+        transmitter.send(header()).unwrap();
+        transmitter.send(cluster(0, true)).unwrap();
+        transmitter.send(cluster(1, false)).unwrap();
+        assert_eq!(replayed(&mut Listener::with_offset(channel.clone(), Duration::from_secs(3))), vec![9, 10, 11]);
+
+        // a different header invalidates the buffer
+        transmitter.send(Chunk::Headers { bytes: Bytes::from_static(b"other") }).unwrap();
+        transmitter.send(cluster(0, true)).unwrap();
+        assert_eq!(replayed(&mut Listener::with_offset(channel, Duration::from_secs(600))), vec![12]);
+    }
+
+    #[test]
+    fn buffer_cleared_with_transmitter() {
+        let (channel, transmitter) = buffered_channel(BufferLimits {
+            duration: Duration::from_secs(60),
+            bytes: 1_000_000,
+        });
+        drop(transmitter);
+        let mut listener = Listener::new(channel);
+        assert!(listener.next().now_or_never().is_none());
+    }
+
+    fn slow_listener(limit: QueueLimit, policy: SlowListenerPolicy) -> (Transmitter, Listener) {
+        let channel = Channel::with_settings("test".into(), ChannelSettings {
+            gop_cache_bytes: 0,
+            listener_queue: limit,
+            slow_listener_policy: policy,
+            ..ChannelSettings::default()
+        });
+        let listener = Listener::new(channel.clone());
+        let transmitter = Transmitter::new(channel).unwrap();
+        transmitter.send(header()).unwrap();
+        (transmitter, listener)
+    }
+
+    fn send_clusters(transmitter: &Transmitter, seconds: std::ops::Range<u64>) {
+        // keyframes every 3 seconds
+        for second in seconds {
+            transmitter.send(cluster(second, second % 3 == 0)).unwrap();
+        }
+    }
+
+    #[test]
+    fn slow_listener_disconnected() {
+        let (transmitter, mut listener) = slow_listener(QueueLimit::Clusters(2), SlowListenerPolicy::Disconnect);
+        send_clusters(&transmitter, 0..5);
+        assert_eq!(replayed(&mut listener), vec![0, 1]);
+        assert!(matches!(listener.next().now_or_never(), Some(None)));
+
+        // clusters are about 115 bytes each
+        let (transmitter, mut listener) = slow_listener(QueueLimit::Bytes(250), SlowListenerPolicy::Disconnect);
+        send_clusters(&transmitter, 0..5);
+        assert_eq!(replayed(&mut listener), vec![0, 1]);
+        assert!(matches!(listener.next().now_or_never(), Some(None)));
+    }
+
+    #[test]
+    fn counters_kept() {
+        let (transmitter, mut listener) = slow_listener(QueueLimit::Clusters(2), SlowListenerPolicy::Disconnect);
+        send_clusters(&transmitter, 0..5);
+        replayed(&mut listener);
+
+        let channel = transmitter.channel.lock().unwrap();
+        let counters = channel.counters();
+        let header_size = header().byte_size() as u64;
+        let cluster_size = cluster(0, true).byte_size() as u64;
+        assert_eq!(counters.bytes_in, header_size + 5 * cluster_size);
+        // the header & two clusters were queued before the listener was dropped
+        assert_eq!(counters.bytes_out, header_size + 2 * cluster_size);
+        assert_eq!(counters.listeners_dropped, 1);
+        // each cluster spans 999ms
+        assert_eq!(counters.cluster_durations, [0, 0, 5, 5, 5, 5]);
+        assert_eq!(counters.clusters, 5);
+        assert_eq!(counters.cluster_duration_sum, Duration::from_millis(5 * 999));
+    }
+
+    #[test]
+    fn slow_listener_skips_to_keyframe() {
+        let (transmitter, mut listener) = slow_listener(QueueLimit::Clusters(2), SlowListenerPolicy::SkipToKeyframe);
+        send_clusters(&transmitter, 0..4);
+        // the keyframe at 3 arrived before the queue drained
+        assert_eq!(replayed(&mut listener), vec![0, 1]);
+
+        send_clusters(&transmitter, 4..8);
+        assert_eq!(replayed(&mut listener), vec![6, 7]);
+        assert!(listener.next().now_or_never().is_none());
+    }
+
+    #[test]
+    fn slow_listener_drops_non_keyframes() {
+        let (transmitter, mut listener) = slow_listener(QueueLimit::Clusters(2), SlowListenerPolicy::DropNonKeyframes);
+        send_clusters(&transmitter, 0..9);
+        assert_eq!(replayed(&mut listener), vec![0, 1, 3, 6]);
+
+        send_clusters(&transmitter, 9..11);
+        assert_eq!(replayed(&mut listener), vec![9, 10]);
+
+        // a listener that never reads is eventually dropped
+        send_clusters(&transmitter, 11..30);
+        assert_eq!(replayed(&mut listener), vec![11, 12, 15, 18]);
+        assert!(matches!(listener.next().now_or_never(), Some(None)));
+    }
+
+    #[test]
+    fn slow_listener_policy_names() {
+        assert_eq!("skip-to-keyframe".parse(), Ok(SlowListenerPolicy::SkipToKeyframe));
+        assert_eq!("drop-non-keyframes".parse(), Ok(SlowListenerPolicy::DropNonKeyframes));
+        assert!("ignore".parse::<SlowListenerPolicy>().is_err());
+    }
+
+    fn policy_channel(policy: TransmitterPolicy) -> Handle {
+        Channel::with_settings("test".into(), ChannelSettings {
+            transmitter_policy: policy,
+            ..ChannelSettings::default()
+        })
+    }
+
+    #[test]
+    fn second_source_rejected() {
+        let channel = policy_channel(TransmitterPolicy::Reject);
+        let first = Transmitter::new(channel.clone()).unwrap();
+        assert!(matches!(Transmitter::new(channel.clone()), Err(ChannelError::Busy)));
+
+        drop(first);
+        assert!(Transmitter::new(channel).is_ok());
+    }
+
+    #[test]
+    fn second_source_takes_over() {
+        let channel = policy_channel(TransmitterPolicy::Takeover);
+        let first = Transmitter::new(channel.clone()).unwrap();
+        first.send(header()).unwrap();
+        first.send(cluster(0, true)).unwrap();
+        let mut listener = Listener::new(channel.clone());
+        assert_eq!(replayed(&mut listener), vec![0]);
+
+        let second = Transmitter::new(channel.clone()).unwrap();
+        assert!(matches!(first.send(cluster(1, false)), Err(ChannelError::Superseded)));
+        second.send(header()).unwrap();
+        // the new source is only relayed from its first keyframe, spliced after the old source
+        second.send(cluster(5, false)).unwrap();
+        second.send(cluster(6, true)).unwrap();
+        assert_eq!(replayed(&mut listener), vec![1]);
+
+        // the superseded source leaving doesn't disturb the new one
+        drop(first);
+        assert_eq!(replayed(&mut Listener::new(channel)), vec![1]);
+    }
+
+    #[test]
+    fn standby_source_waits() {
+        let channel = policy_channel(TransmitterPolicy::Standby);
+        let first = Transmitter::new(channel.clone()).unwrap();
+        first.send(header()).unwrap();
+        let mut listener = Listener::new(channel.clone());
+        for second in 0..3 {
+            first.send(cluster(second, second == 0)).unwrap();
+        }
+        assert_eq!(replayed(&mut listener), vec![0, 1, 2]);
+
+        let standby = Transmitter::new(channel).unwrap();
+        assert!(matches!(standby.send(Chunk::Headers { bytes: Bytes::from_static(b"standby") }), Ok(false)));
+        assert!(matches!(standby.send(cluster(0, true)), Ok(false)));
+        standby.send(cluster(1, false)).unwrap();
+        assert_eq!(replayed(&mut listener), Vec::<u64>::new());
+
+        drop(first);
+        match listener.next().now_or_never() {
+            Some(Some(Chunk::Headers { bytes })) => assert_eq!(bytes.as_ref(), b"standby"),
+            _ => panic!("standby header should be sent on switching")
+        }
+        assert!(matches!(standby.send(cluster(2, false)), Ok(true)));
+        standby.send(cluster(3, true)).unwrap();
+        assert_eq!(replayed(&mut listener), vec![3]);
+    }
+
+    #[test]
+    fn transmitter_policy_names() {
+        assert_eq!("standby".parse(), Ok(TransmitterPolicy::Standby));
+        assert!("share".parse::<TransmitterPolicy>().is_err());
+    }
+
+    fn backup_channel(failover_timeout: Duration) -> (Handle, Transmitter, Transmitter, Listener) {
+        let channel = Channel::with_settings("test".into(), ChannelSettings {
+            failover_timeout,
+            ..ChannelSettings::default()
+        });
+        let primary = Transmitter::new(channel.clone()).unwrap();
+        let backup = Transmitter::backup(channel.clone()).unwrap();
+        primary.send(header()).unwrap();
+        backup.send(Chunk::Headers { bytes: Bytes::from_static(b"backup") }).unwrap();
+        let listener = Listener::new(channel.clone());
+        (channel, primary, backup, listener)
+    }
+
+    fn next_header(listener: &mut Listener) -> Bytes {
+        match listener.next().now_or_never() {
+            Some(Some(Chunk::Headers { bytes })) => bytes,
+            _ => panic!("Expected a header")
+        }
+    }
+
+    #[test]
+    fn backup_takes_over_on_disconnect() {
+        let (channel, primary, backup, mut listener) = backup_channel(DEFAULT_FAILOVER_TIMEOUT);
+        assert!(matches!(Transmitter::backup(channel), Err(ChannelError::Busy)));
+        assert_eq!(next_header(&mut listener).as_ref(), b"header");
+
+        assert!(matches!(primary.send(cluster(0, true)), Ok(true)));
+        primary.send(cluster(1, false)).unwrap();
+        assert!(matches!(backup.send(cluster(30, true)), Ok(false)));
+        assert_eq!(replayed(&mut listener), vec![0, 1]);
+
+        drop(primary);
+        assert_eq!(next_header(&mut listener).as_ref(), b"backup");
+        // switches at the backup's next keyframe, continuing the timeline
+        backup.send(cluster(31, false)).unwrap();
+        backup.send(cluster(32, true)).unwrap();
+        assert_eq!(replayed(&mut listener), vec![2]);
+    }
+
+    #[test]
+    fn backup_takes_over_on_stall() {
+        let (channel, primary, backup, mut listener) = backup_channel(DEFAULT_FAILOVER_TIMEOUT);
+        primary.send(cluster(0, true)).unwrap();
+        backup.send(cluster(0, true)).unwrap();
+        assert_eq!(replayed(&mut listener), vec![0]);
+
+        // rather than waiting out the timeout, pretend the primary's last chunk is that old
+        {
+            let mut channel = channel.lock().unwrap();
+            channel.last_publish = Instant::now().checked_sub(DEFAULT_FAILOVER_TIMEOUT).unwrap();
+        }
+        assert!(matches!(backup.send(cluster(1, true)), Ok(true)));
+        assert_eq!(next_header(&mut listener).as_ref(), b"backup");
+        assert_eq!(replayed(&mut listener), vec![1]);
+        assert!(matches!(primary.send(cluster(1, false)), Err(ChannelError::Superseded)));
+    }
+
+    #[test]
+    fn primary_replaces_backup() {
+        let channel = Channel::new("test".into());
+        let backup = Transmitter::backup(channel.clone()).unwrap();
+        // with no primary source, the backup is relayed right away
+        backup.send(header()).unwrap();
+        backup.send(cluster(0, true)).unwrap();
+        let mut listener = Listener::new(channel.clone());
+        assert_eq!(replayed(&mut listener), vec![0]);
+
+        let primary = Transmitter::new(channel).unwrap();
+        primary.send(header()).unwrap();
+        primary.send(cluster(0, true)).unwrap();
+        backup.send(cluster(1, true)).unwrap();
+        assert_eq!(replayed(&mut listener), vec![1]);
+        assert!(listener.next().now_or_never().is_none());
+    }
+
+    #[test]
+    fn slate_fills_gaps() {
+        let channel = Channel::new("test".into());
+        let source = Transmitter::new(channel.clone()).unwrap();
+        source.send(tracks_header("V_VP9")).unwrap();
+        let mut listener = Listener::new(channel.clone());
+        source.send(cluster(0, true)).unwrap();
+        source.send(cluster(1, false)).unwrap();
+
+        channel.lock().unwrap().send_slate(tracks_header("V_VP9"));
+        channel.lock().unwrap().send_slate(cluster(0, true));
+        assert_eq!(replayed(&mut listener), vec![0, 1]);
+
+        // once the source leaves, the slate takes over at its next keyframe
+        drop(source);
+        channel.lock().unwrap().send_slate(cluster(1, false));
+        channel.lock().unwrap().send_slate(cluster(2, true));
+        assert_eq!(replayed(&mut listener), vec![2]);
+
+        // and a returning source replaces it
+        let source = Transmitter::new(channel.clone()).unwrap();
+        source.send(tracks_header("V_VP9")).unwrap();
+        source.send(cluster(0, true)).unwrap();
+        channel.lock().unwrap().send_slate(cluster(3, true));
+        assert_eq!(replayed(&mut listener), vec![3]);
+    }
+
+    #[test]
+    fn incompatible_slate_not_relayed() {
+        let channel = Channel::new("test".into());
+        let source = Transmitter::new(channel.clone()).unwrap();
+        source.send(tracks_header("V_VP9")).unwrap();
+        source.send(cluster(0, true)).unwrap();
+        let mut listener = Listener::new(channel.clone());
+        assert_eq!(replayed(&mut listener), vec![0]);
+
+        drop(source);
+        channel.lock().unwrap().send_slate(tracks_header("V_VP8"));
+        channel.lock().unwrap().send_slate(cluster(0, true));
+        assert!(listener.next().now_or_never().is_none());
+    }
+
+    #[test]
+    fn slate_with_other_timecode_scale_not_relayed() {
+        let channel = Channel::new("test".into());
+        let source = Transmitter::new(channel.clone()).unwrap();
+        source.send(tracks_header("V_VP9")).unwrap();
+        source.send(cluster(0, true)).unwrap();
+        let mut listener = Listener::new(channel.clone());
+        assert_eq!(replayed(&mut listener), vec![0]);
+
+        drop(source);
+        channel.lock().unwrap().send_slate(scaled_header("V_VP9", 100_000));
+        channel.lock().unwrap().send_slate(cluster(0, true));
+        assert!(listener.next().now_or_never().is_none());
+    }
+
+    #[test]
+    fn status_reported() {
+        let channel = Channel::with_settings("test".into(), ChannelSettings {
+            listener_queue: QueueLimit::Clusters(20),
+            ..ChannelSettings::default()
+        });
+        let status = channel.lock().unwrap().status();
+        assert!(!status.transmitter_connected);
+        assert_eq!((status.listeners, status.bitrate, status.last_timecode_ms), (0, None, None));
+
+        let source = Transmitter::new(channel.clone()).unwrap();
+        source.send(tracks_header("V_VP9")).unwrap();
+        let _listener = Listener::new(channel.clone());
+        drop(Listener::new(channel.clone()));
+        for second in 0..10 {
+            source.send(cluster(second, true)).unwrap();
+        }
+
+        let status = channel.lock().unwrap().status();
+        assert!(status.transmitter_connected);
+        assert_eq!(status.listeners, 1);
+        assert_eq!(status.codecs, vec!["V_VP9".to_string()]);
+        assert_eq!(status.uptime_seconds, Some(0));
+        assert_eq!(status.last_timecode_ms, Some(9000));
+        // clusters 5-9 span 4.999 seconds of the window
+        let size = cluster(0, true).byte_size() as u64;
+        assert_eq!(status.bitrate, Some(5 * size * 8 * 1000 / 4999));
+
+        drop(source);
+        let status = channel.lock().unwrap().status();
+        assert!(!status.transmitter_connected);
+        assert!(status.codecs.is_empty());
+        assert_eq!((status.bitrate, status.uptime_seconds), (None, None));
+    }
+
+    #[test]
+    fn kicked_source_replaced() {
+        let channel = policy_channel(TransmitterPolicy::Standby);
+        let first = Transmitter::new(channel.clone()).unwrap();
+        let second = Transmitter::new(channel.clone()).unwrap();
+        second.send(header()).unwrap();
+        let mut kicked = first.kicked();
+        assert!((&mut kicked).now_or_never().is_none());
+        let mut listener = Listener::new(channel.clone());
+
+        assert!(channel.lock().unwrap().kick_transmitter());
+        assert_eq!(kicked.now_or_never(), Some(()));
+        assert!(matches!(first.send(cluster(0, true)), Err(ChannelError::Kicked)));
+        assert!(second.kicked().now_or_never().is_none());
+
+        // the standby source took over
+        second.send(cluster(0, true)).unwrap();
+        assert_eq!(replayed(&mut listener), vec![0]);
+
+        drop(second);
+        assert!(!channel.lock().unwrap().kick_transmitter());
+    }
+
+    #[test]
+    fn idle_without_source() {
+        let channel = policy_channel(TransmitterPolicy::Standby);
+        assert_eq!(Channel::idle(&channel).now_or_never(), Some(()));
+
+        let first = Transmitter::new(channel.clone()).unwrap();
+        let second = Transmitter::new(channel.clone()).unwrap();
+        let mut idle = Channel::idle(&channel);
+        assert!((&mut idle).now_or_never().is_none());
+        assert!(channel.lock().unwrap().has_source());
+
+        // the standby takes over, so the channel isn't idle yet
+        drop(first);
+        assert!((&mut idle).now_or_never().is_none());
+        drop(second);
+        assert_eq!(idle.now_or_never(), Some(()));
+        assert!(!channel.lock().unwrap().has_source());
+    }
+
+    #[test]
+    fn listeners_kicked() {
+        let (channel, transmitter) = buffered_channel(BufferLimits::default());
+        let mut listeners = vec![Listener::new(channel.clone()), Listener::new(channel.clone())];
+        drop(listeners.pop());
+        assert_eq!(channel.lock().unwrap().kick_listeners(), 1);
+        // what was already replayed is still delivered, then the stream ends
+        while let Some(Some(_)) = listeners[0].next().now_or_never() {}
+        assert!(matches!(listeners[0].next().now_or_never(), Some(None)));
+
+        // new listeners can still join
+        let mut listener = Listener::new(channel);
+        transmitter.send(cluster(10, true)).unwrap();
+        assert_eq!(replayed(&mut listener), vec![10]);
     }
 }
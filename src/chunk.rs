@@ -8,13 +8,21 @@ use std::{
 };
 use crate::stream_parser::EbmlStreamingParser;
 use crate::error::WebmetroError;
+use crate::info::{encode_info, parse_info, SegmentInfo, DEFAULT_TIMECODE_SCALE};
+use crate::tracks::{parse_tracks, TrackEntry, TrackType};
 use crate::webm::*;
 
 #[derive(Clone, Debug)]
 pub struct ClusterHead {
     pub keyframe: bool,
+    /// in timecode ticks; see `start_nanos()` for real time
     pub start: u64,
+    /// in timecode ticks; see `end_nanos()` for real time
     pub end: u64,
+    /// nanoseconds per timecode tick, as given by the stream's Info element
+    pub timecode_scale: u64,
+    /// whether a block from a video track has been seen in this cluster yet
+    seen_video_block: bool,
     /// a Cluster tag and a Timecode tag together take at most 15 bytes;
     /// fortuitously, 15 bytes can be inlined in a Bytes handle even on 32-bit systems
     bytes: BytesMut,
@@ -26,11 +34,23 @@ impl ClusterHead {
             keyframe: false,
             start: 0,
             end: 0,
+            timecode_scale: DEFAULT_TIMECODE_SCALE,
+            seen_video_block: false,
             bytes: BytesMut::with_capacity(15),
         };
         cluster_head.update_timecode(timecode);
         cluster_head
     }
+    pub fn with_timecode_scale(mut self, timecode_scale: u64) -> Self {
+        self.timecode_scale = timecode_scale;
+        self
+    }
+    pub fn start_nanos(&self) -> u128 {
+        self.start as u128 * self.timecode_scale as u128
+    }
+    pub fn end_nanos(&self) -> u128 {
+        self.end as u128 * self.timecode_scale as u128
+    }
     pub fn update_timecode(&mut self, timecode: u64) {
         let delta = self.end - self.start;
         self.start = timecode;
@@ -50,6 +70,14 @@ impl ClusterHead {
             self.end = absolute_timecode;
         }
     }
+    /// A cluster is only a safe place to start decoding if the first video block in it
+    /// is a keyframe; blocks from other tracks don't affect this.
+    pub fn observe_video_block(&mut self, keyframe: bool) {
+        if !self.seen_video_block {
+            self.seen_video_block = true;
+            self.keyframe = keyframe;
+        }
+    }
 }
 
 /// A chunk of WebM data
@@ -68,9 +96,46 @@ pub enum Chunk {
 }
 
 impl Chunk {
+    /// The number of bytes this chunk will write
+    pub fn byte_size(&self) -> usize {
+        match self {
+            Chunk::Headers { bytes } => bytes.len(),
+            Chunk::Cluster(head, body) => head.bytes.len() + body.len(),
+            Chunk::RemainingBody(bytes) => bytes.len(),
+            Chunk::Empty => 0,
+        }
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        matches!(self, Chunk::Cluster(ClusterHead { keyframe: true, .. }, _))
+    }
+
+    /// The tracks described by a Headers chunk, if it has a valid Tracks element
+    pub fn tracks(&self) -> Option<Vec<TrackEntry>> {
+        match self {
+            Chunk::Headers { bytes } => parse_webm(bytes).find_map(|element| match element {
+                WebmElement::Tracks(tracks) => parse_tracks(tracks).ok(),
+                _ => None
+            }),
+            _ => None
+        }
+    }
+
+    /// The TimecodeScale of a Headers chunk: its Info element's, or the default without one
+    pub fn timecode_scale(&self) -> Option<u64> {
+        match self {
+            Chunk::Headers { bytes } => Some(parse_webm(bytes).find_map(|element| match element {
+                WebmElement::Info(info) => parse_info(info).ok(),
+                _ => None
+            }).map_or(DEFAULT_TIMECODE_SCALE, |info| info.timecode_scale)),
+            _ => None
+        }
+    }
+
+    /// Check if this chunk overlaps the given time range, in nanoseconds
     pub fn overlaps(&self, start: u128, stop: u128) -> bool {
         match self {
-            Chunk::Cluster(head, _) => head.start as u128 <= stop && head.end as u128 >= start,
+            Chunk::Cluster(head, _) => head.start_nanos() <= stop && head.end_nanos() >= start,
             _ => true,
         }
     }
@@ -114,27 +179,47 @@ enum ChunkerState {
 pub struct WebmChunker<S> {
     source: EbmlStreamingParser<S>,
     buffer_size_limit: Option<usize>,
+    /// track numbers of video tracks, as learned from the most recent Tracks element
+    video_tracks: Vec<u64>,
+    /// as learned from the most recent Info element
+    timecode_scale: u64,
     state: ChunkerState,
 }
 
 impl<S> WebmChunker<S> {
     /// add a "soft" buffer size limit; if a chunk buffer exceeds this size,
-    /// error the stream instead of resuming. It's still possible for a buffer
-    /// to exceed this size *after* a write, so ensure input sizes are reasonable.
+    /// error the stream instead of resuming. The element that pushed the buffer
+    /// over the limit has already been written, so ensure input sizes are reasonable.
     pub fn with_soft_limit(mut self, limit: usize) -> Self {
         self.buffer_size_limit = Some(limit);
         self
     }
 }
 
+/// Streams without any (known) video tracks are treated as if every track were video,
+/// so audio-only streams can still start on a keyframe block
+fn is_video_track(video_tracks: &[u64], track: u64) -> bool {
+    video_tracks.is_empty() || video_tracks.contains(&track)
+}
+
 fn encode(element: WebmElement, buffer: &mut Cursor<Vec<u8>>, limit: Option<usize>) -> Result<(), WebmetroError> {
-    if let Some(limit) = limit {
-        if limit <= buffer.get_ref().len() {
-            return Err(WebmetroError::ResourcesExceeded);
-        }
+    encode_webm_element(element, buffer)?;
+
+    match limit {
+        Some(limit) if buffer.get_ref().len() > limit => Err(WebmetroError::ResourcesExceeded {
+            buffer: "chunk",
+            size: buffer.get_ref().len(),
+            limit,
+        }),
+        _ => Ok(()),
     }
+}
 
-    encode_webm_element(element, buffer).map_err(|err| err.into())
+/// Keep the Info element, minus fields that would be wrong for a live stream
+fn encode_live_info(info: &SegmentInfo, buffer: &mut Cursor<Vec<u8>>, limit: Option<usize>) -> Result<(), WebmetroError> {
+    let mut live_info = Vec::new();
+    encode_info(&info.for_live_stream(), &mut live_info)?;
+    encode(WebmElement::Info(&live_info), buffer, limit)
 }
 
 impl<I: Buf, E, S: Stream<Item = Result<I, E>> + Unpin> Stream for WebmChunker<S>
@@ -144,7 +229,7 @@ where
     type Item = Result<Chunk, WebmetroError>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Chunk, WebmetroError>>> {
-        let mut chunker = self.get_mut();
+        let chunker = self.get_mut();
         loop {
             match chunker.state {
                 ChunkerState::BuildingHeader(ref mut buffer) => {
@@ -158,15 +243,40 @@ where
                                 let header_chunk = Chunk::Headers {bytes: Bytes::from(liberated_buffer.into_inner())};
 
                                 chunker.state = ChunkerState::BuildingCluster(
-                                    ClusterHead::new(0),
+                                    ClusterHead::new(0).with_timecode_scale(chunker.timecode_scale),
                                     Cursor::new(Vec::new())
                                 );
                                 return Ready(Some(Ok(header_chunk)));
                             },
-                            WebmElement::Info => {},
+                            WebmElement::Info(info) => {
+                                // as with Tracks, a bad Info isn't worth cutting the stream off for
+                                let info = parse_info(info).unwrap_or_else(|err| {
+                                    warn!("Couldn't parse the Info element ({}); using default values", err);
+                                    SegmentInfo::default()
+                                });
+                                chunker.timecode_scale = info.timecode_scale;
+                                if let Err(err) = encode_live_info(&info, buffer, chunker.buffer_size_limit) {
+                                    chunker.state = ChunkerState::End;
+                                    return Ready(Some(Err(err)));
+                                }
+                            },
                             WebmElement::Void => {},
                             WebmElement::Unknown(_) => {},
                             element => {
+                                if let WebmElement::Tracks(tracks) = element {
+                                    match parse_tracks(tracks) {
+                                        Ok(tracks) => chunker.video_tracks = tracks.iter()
+                                            .filter(|track| track.track_type == TrackType::Video)
+                                            .map(|track| track.number)
+                                            .collect(),
+                                        // the stream may still play fine, so don't cut it off;
+                                        // with no known video tracks, every track counts as video
+                                        Err(err) => {
+                                            warn!("Couldn't parse the Tracks element ({}); treating every track as video", err);
+                                            chunker.video_tracks.clear();
+                                        }
+                                    }
+                                }
                                 if let Err(err) = encode(element, buffer, chunker.buffer_size_limit) {
                                     chunker.state = ChunkerState::End;
                                     return Ready(Some(Err(err)));
@@ -176,6 +286,8 @@ where
                     }
                 },
                 ChunkerState::BuildingCluster(ref mut cluster_head, ref mut buffer) => {
+                    let video_tracks = &chunker.video_tracks;
+                    let timecode_scale = chunker.timecode_scale;
                     match chunker.source.poll_event(cx) {
                         Ready(Some(Err(passthru))) => return Ready(Some(Err(passthru))),
                         Pending => return Pending,
@@ -197,7 +309,7 @@ where
                                 }
                             },
                             WebmElement::Cluster => {
-                                let liberated_cluster_head = mem::replace(cluster_head, ClusterHead::new(0));
+                                let liberated_cluster_head = mem::replace(cluster_head, ClusterHead::new(0).with_timecode_scale(timecode_scale));
                                 let liberated_buffer = mem::replace(buffer, Cursor::new(Vec::new()));
 
                                 return Ready(Some(Ok(Chunk::Cluster(liberated_cluster_head, Bytes::from(liberated_buffer.into_inner())))));
@@ -206,9 +318,8 @@ where
                                 cluster_head.update_timecode(timecode);
                             },
                             WebmElement::SimpleBlock(ref block) => {
-                                if (block.flags & 0b10000000) != 0 {
-                                    // TODO: this is incorrect, condition needs to also affirm we're the first video block of the cluster
-                                    cluster_head.keyframe = true;
+                                if is_video_track(video_tracks, block.track) {
+                                    cluster_head.observe_video_block((block.flags & 0b10000000) != 0);
                                 }
                                 cluster_head.observe_simpleblock_timecode(block.timecode);
                                 if let Err(err) = encode(WebmElement::SimpleBlock(*block), buffer, chunker.buffer_size_limit) {
@@ -216,7 +327,23 @@ where
                                     return Ready(Some(Err(err)));
                                 }
                             },
-                            WebmElement::Info => {},
+                            WebmElement::BlockGroup(data) => {
+                                match parse_block_group(data) {
+                                    Ok(group) => {
+                                        if is_video_track(video_tracks, group.block.track) {
+                                            cluster_head.observe_video_block(group.is_keyframe());
+                                        }
+                                        cluster_head.observe_simpleblock_timecode(group.block.timecode);
+                                    },
+                                    // a player may make sense of it, so it's relayed as-is
+                                    Err(err) => warn!("Couldn't parse a BlockGroup ({}); relaying it unchecked", err),
+                                }
+                                if let Err(err) = encode(WebmElement::BlockGroup(data), buffer, chunker.buffer_size_limit) {
+                                    chunker.state = ChunkerState::End;
+                                    return Ready(Some(Err(err)));
+                                }
+                            },
+                            WebmElement::Info(_) => {},
                             WebmElement::Void => {},
                             WebmElement::Unknown(_) => {},
                             element => {
@@ -253,6 +380,8 @@ impl<S: Stream> WebmStream for EbmlStreamingParser<S> {
         WebmChunker {
             source: self,
             buffer_size_limit: None,
+            video_tracks: Vec::new(),
+            timecode_scale: DEFAULT_TIMECODE_SCALE,
             state: ChunkerState::BuildingHeader(Cursor::new(Vec::new()))
         }
     }
@@ -261,10 +390,278 @@ impl<S: Stream> WebmStream for EbmlStreamingParser<S> {
 #[cfg(test)]
 mod tests {
 
+    use futures::FutureExt;
+
     use crate::chunk::*;
+    use crate::stream_parser::StreamEbml;
+    use crate::tests::{scaled_header, tracks_header, TEST_FILE};
 
     #[test]
     fn enough_space_for_header() {
-        ClusterHead::new(u64::max_value());
+        ClusterHead::new(u64::MAX);
+    }
+
+    fn chunk_all(bytes: &[u8]) -> Vec<Chunk> {
+        futures::stream::iter(Some(Ok::<&[u8], WebmetroError>(bytes)))
+            .parse_ebml()
+            .chunk_webm()
+            .try_collect()
+            .now_or_never()
+            .expect("Test tried to block on I/O")
+            .expect("Chunking failed")
+    }
+
+    fn keyframe_flags(chunks: &[Chunk]) -> Vec<bool> {
+        chunks.iter().filter_map(|chunk| match chunk {
+            Chunk::Cluster(head, _) => Some(head.keyframe),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn oversized_chunk_rejected() {
+        let result: Result<Vec<Chunk>, WebmetroError> = futures::stream::iter(Some(Ok::<&[u8], WebmetroError>(TEST_FILE)))
+            .parse_ebml()
+            .chunk_webm()
+            .with_soft_limit(1000)
+            .try_collect()
+            .now_or_never()
+            .expect("Test tried to block on I/O");
+        match result {
+            Err(WebmetroError::ResourcesExceeded { buffer: "chunk", size, limit: 1000 }) => assert!(size > 1000),
+            other => panic!("Expected the chunk limit to be exceeded, got {:?}", other.map(|chunks| chunks.len())),
+        }
+    }
+
+    #[test]
+    fn video_keyframes() {
+        let chunks = chunk_all(TEST_FILE);
+        assert_eq!(keyframe_flags(&chunks), vec![true, true, true]);
+    }
+
+    #[test]
+    fn header_keeps_live_info() {
+        let chunks = chunk_all(TEST_FILE);
+        let header: Vec<u8> = match &chunks[0] {
+            Chunk::Headers { bytes } => bytes.to_vec(),
+            other => panic!("Expected headers, got {:?}", other)
+        };
+
+        let mut elements = parse_webm(&header);
+        assert_eq!(elements.next(), Some(WebmElement::EbmlHead));
+        assert_eq!(elements.next(), Some(WebmElement::Segment));
+        match elements.next() {
+            Some(WebmElement::Info(body)) => {
+                let info = parse_info(body).unwrap();
+                assert_eq!(info.timecode_scale, 1_000_000);
+                assert_eq!(info.muxing_app, "Lavf57.56.100");
+                assert_eq!(info.duration, None);
+            },
+            other => panic!("Expected Info, got {:?}", other)
+        }
+        assert_eq!(elements.next(), Some(WebmElement::Tracks(&TEST_FILE[358..421])));
+        assert_eq!(elements.next(), None);
+    }
+
+    #[test]
+    fn header_tracks() {
+        let chunks = chunk_all(TEST_FILE);
+        let tracks = chunks[0].tracks().unwrap();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].codec_id, "V_VP9");
+        assert_eq!(chunks[1].tracks(), None);
+    }
+
+    #[test]
+    fn header_timecode_scale() {
+        assert_eq!(scaled_header("V_VP9", 100_000).timecode_scale(), Some(100_000));
+        assert_eq!(tracks_header("V_VP9").timecode_scale(), Some(DEFAULT_TIMECODE_SCALE));
+        assert_eq!(chunk_all(TEST_FILE)[1].timecode_scale(), None);
+    }
+
+    #[test]
+    fn audio_keyframes_ignored() {
+        let mut cursor = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead, &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Segment, &mut cursor).unwrap();
+        // track 1 is video, track 2 is audio
+        encode_webm_element(WebmElement::Tracks(&[
+            0xAE, 0x86, 0xD7, 0x81, 0x01, 0x83, 0x81, 0x01,
+            0xAE, 0x86, 0xD7, 0x81, 0x02, 0x83, 0x81, 0x02,
+        ]), &mut cursor).unwrap();
+
+        let block = |track, flags| WebmElement::SimpleBlock(SimpleBlock {
+            track,
+            timecode: 0,
+            flags,
+            data: &[]
+        });
+        for (audio_flags, video_flags) in [(0x80, 0x00), (0x80, 0x80), (0x00, 0x80)] {
+            encode_webm_element(WebmElement::Cluster, &mut cursor).unwrap();
+            encode_webm_element(WebmElement::Timecode(0), &mut cursor).unwrap();
+            encode_webm_element(block(2, audio_flags), &mut cursor).unwrap();
+            encode_webm_element(block(1, video_flags), &mut cursor).unwrap();
+            encode_webm_element(block(1, 0x80), &mut cursor).unwrap();
+        }
+
+        let chunks = chunk_all(cursor.get_ref());
+        assert_eq!(keyframe_flags(&chunks), vec![false, true, true]);
+    }
+
+    #[test]
+    fn corrupt_tracks_passed_through() {
+        let mut cursor = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead, &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Segment, &mut cursor).unwrap();
+        // the TrackEntry claims more content than it has
+        encode_webm_element(WebmElement::Tracks(&[0xAE, 0x84, 0xD7, 0x81, 0x01]), &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Cluster, &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Timecode(0), &mut cursor).unwrap();
+        encode_webm_element(WebmElement::SimpleBlock(SimpleBlock {
+            track: 2,
+            timecode: 0,
+            flags: 0x80,
+            data: &[]
+        }), &mut cursor).unwrap();
+
+        // without knowing which tracks are video, any keyframe block counts
+        let chunks = chunk_all(cursor.get_ref());
+        assert!(matches!(chunks[0], Chunk::Headers { .. }));
+        assert_eq!(keyframe_flags(&chunks), vec![true]);
+    }
+
+    #[test]
+    fn timecode_scale_applied() {
+        let chunks = chunk_all(TEST_FILE);
+        if let Chunk::Cluster(head, _) = &chunks[2] {
+            assert_eq!(head.timecode_scale, 1_000_000);
+            assert_eq!(head.start_nanos(), 1_000_000_000);
+        } else {
+            panic!("Expected a cluster");
+        }
+
+        let mut cursor = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead, &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Segment, &mut cursor).unwrap();
+        // TimecodeScale = 100000ns (0.1ms ticks)
+        encode_webm_element(WebmElement::Info(&[0x2A, 0xD7, 0xB1, 0x83, 0x01, 0x86, 0xA0]), &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Cluster, &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Timecode(10000), &mut cursor).unwrap();
+        encode_webm_element(WebmElement::SimpleBlock(SimpleBlock {
+            track: 1,
+            timecode: 330,
+            flags: 0x80,
+            data: &[]
+        }), &mut cursor).unwrap();
+
+        let chunks = chunk_all(cursor.get_ref());
+        match &chunks[..] {
+            [Chunk::Headers {..}, Chunk::Cluster(head, _)] => {
+                assert_eq!(head.timecode_scale, 100_000);
+                assert_eq!((head.start, head.end), (10000, 10330));
+                assert_eq!((head.start_nanos(), head.end_nanos()), (1_000_000_000, 1_033_000_000));
+                assert!(chunks[1].overlaps(1_020_000_000, 2_000_000_000));
+                assert!(!chunks[1].overlaps(1_040_000_000, 2_000_000_000));
+            },
+            other => panic!("Unexpected chunks {:?}", other)
+        }
+    }
+
+    #[test]
+    fn corrupt_info_replaced() {
+        for info in [
+            // TimecodeScale = 0
+            &[0x2A, 0xD7, 0xB1, 0x81, 0x00][..],
+            // a Title that isn't UTF-8
+            &[0x7B, 0xA9, 0x81, 0xFF][..],
+        ] {
+            let mut cursor = Cursor::new(Vec::new());
+            encode_webm_element(WebmElement::EbmlHead, &mut cursor).unwrap();
+            encode_webm_element(WebmElement::Segment, &mut cursor).unwrap();
+            encode_webm_element(WebmElement::Info(info), &mut cursor).unwrap();
+            encode_webm_element(WebmElement::Cluster, &mut cursor).unwrap();
+            encode_webm_element(WebmElement::Timecode(1000), &mut cursor).unwrap();
+
+            let chunks = chunk_all(cursor.get_ref());
+            let header: Vec<u8> = chunks[0].clone().flatten().collect();
+            let live_info = parse_webm(&header).find_map(|element| match element {
+                WebmElement::Info(info) => Some(parse_info(info).unwrap()),
+                _ => None,
+            });
+            assert_eq!(live_info, Some(SegmentInfo::default()));
+            match &chunks[1] {
+                Chunk::Cluster(head, _) => assert_eq!(head.start_nanos(), 1_000_000_000),
+                other => panic!("Expected a cluster, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn block_groups_chunked() {
+        let mut cursor = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead, &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Segment, &mut cursor).unwrap();
+
+        let group = |timecode, references| BlockGroup {
+            block: SimpleBlock {
+                track: 1,
+                timecode,
+                flags: 0,
+                data: &[1, 2, 3]
+            },
+            duration: Some(33),
+            references,
+            discard_padding: None,
+        };
+        encode_webm_element(WebmElement::Cluster, &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Timecode(1000), &mut cursor).unwrap();
+        encode_block_group(&group(0, vec![]), &mut cursor).unwrap();
+        encode_block_group(&group(33, vec![-33]), &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Cluster, &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Timecode(2000), &mut cursor).unwrap();
+        encode_block_group(&group(0, vec![-967]), &mut cursor).unwrap();
+
+        let chunks = chunk_all(cursor.get_ref());
+        assert_eq!(keyframe_flags(&chunks), vec![true, false]);
+
+        // the groups should pass through the chunker intact
+        let first_cluster: Vec<u8> = chunks[1].clone().flatten().collect();
+        let mut elements = parse_webm(&first_cluster);
+        assert_eq!(elements.next(), Some(WebmElement::Cluster));
+        assert_eq!(elements.next(), Some(WebmElement::Timecode(1000)));
+        for expected in [group(0, vec![]), group(33, vec![-33])] {
+            match elements.next() {
+                Some(WebmElement::BlockGroup(body)) => assert_eq!(parse_block_group(body).unwrap(), expected),
+                other => panic!("Expected BlockGroup, got {:?}", other)
+            }
+        }
+        assert_eq!(elements.next(), None);
+        if let Chunk::Cluster(head, _) = &chunks[1] {
+            assert_eq!((head.start, head.end), (1000, 1033));
+        }
+    }
+
+    #[test]
+    fn corrupt_block_groups_passed_through() {
+        let mut cursor = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead, &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Segment, &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Cluster, &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Timecode(0), &mut cursor).unwrap();
+        encode_webm_element(WebmElement::SimpleBlock(SimpleBlock {
+            track: 1,
+            timecode: 0,
+            flags: 0x80,
+            data: &[]
+        }), &mut cursor).unwrap();
+        // a BlockDuration, but no Block
+        encode_webm_element(WebmElement::BlockGroup(&[0x9B, 0x81, 0x21]), &mut cursor).unwrap();
+
+        let chunks = chunk_all(cursor.get_ref());
+        assert_eq!(keyframe_flags(&chunks), vec![true]);
+        let cluster: Vec<u8> = chunks[1].clone().flatten().collect();
+        let mut elements = parse_webm(&cluster).skip(3);
+        assert_eq!(elements.next(), Some(WebmElement::BlockGroup(&[0x9B, 0x81, 0x21])));
+        assert_eq!(elements.next(), None);
     }
 }
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use custom_error::custom_error;
+use serde::Deserialize;
+use webmetro::error::WebmetroError;
+
+custom_error!{pub AccessDenied
+    Unauthorized = "a key or token is required",
+    Forbidden = "the key or token isn't valid for this channel"
+}
+
+/// Who a channel's keys are for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Publisher,
+    Viewer,
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Role::Publisher => write!(f, "publisher"),
+            Role::Viewer => write!(f, "viewer"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// Keys for one channel; an absent list falls back to the default section
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelAccess {
+    /// keys allowed to publish; if none are configured, nobody may publish
+    pub publish: Option<Vec<String>>,
+    /// tokens allowed to view; if none are configured, anyone may view
+    pub view: Option<Vec<String>>,
+    /// keys allowed to use the admin routes; if none are configured, nobody may
+    pub admin: Option<Vec<String>>,
+}
+
+impl ChannelAccess {
+    fn keys(&self, role: Role) -> Option<&[String]> {
+        match role {
+            Role::Publisher => self.publish.as_deref(),
+            Role::Viewer => self.view.as_deref(),
+            Role::Admin => self.admin.as_deref(),
+        }
+    }
+}
+
+/// Per-channel access rules for the relay, e.g.:
+///
+/// ```toml
+/// metrics = ["metrics scraper key"]
+///
+/// [default]
+/// publish = ["key for any channel"]
+/// admin = ["operator key"]
+///
+/// [channels.main]
+/// publish = ["key for main"]
+/// view = ["viewer token"]
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessRules {
+    /// keys allowed to read the relay's metrics; if none are configured, nobody may
+    #[serde(default)]
+    pub metrics: Vec<String>,
+    #[serde(default)]
+    pub default: ChannelAccess,
+    #[serde(default)]
+    pub channels: HashMap<String, ChannelAccess>,
+}
+
+/// Compare secrets without leaking how much of them matched through timing
+fn secrets_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn check_keys(keys: &[String], credential: Option<&str>) -> Result<(), AccessDenied> {
+    match credential {
+        None => Err(AccessDenied::Unauthorized),
+        Some(credential) if keys.iter().any(|key| secrets_match(credential, key)) => Ok(()),
+        Some(_) => Err(AccessDenied::Forbidden),
+    }
+}
+
+impl AccessRules {
+    pub fn load(path: &Path) -> Result<AccessRules, WebmetroError> {
+        let text = std::fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(|err| WebmetroError::ApplicationError {
+            message: format!("{}: {}", path.display(), err),
+        })
+    }
+
+    fn keys(&self, channel: &str, role: Role) -> Option<&[String]> {
+        self.channels.get(channel)
+            .and_then(|access| access.keys(role))
+            .or_else(|| self.default.keys(role))
+    }
+
+    /// Check whether the credential (if any) lets its holder take the role on the channel
+    pub fn check(&self, channel: &str, role: Role, credential: Option<&str>) -> Result<(), AccessDenied> {
+        let keys = match (self.keys(channel, role), role) {
+            (Some(keys), _) if !keys.is_empty() => keys,
+            (_, Role::Viewer) => return Ok(()),
+            (_, Role::Publisher) | (_, Role::Admin) => return Err(AccessDenied::Forbidden),
+        };
+        check_keys(keys, credential)
+    }
+
+    /// Check whether the credential (if any) lets its holder read the relay's metrics
+    pub fn check_metrics(&self, credential: Option<&str>) -> Result<(), AccessDenied> {
+        if self.metrics.is_empty() {
+            return Err(AccessDenied::Forbidden);
+        }
+        check_keys(&self.metrics, credential)
+    }
+}
+
+/// Find a client's credential in an `Authorization: Bearer` header or a `token` query parameter
+pub fn credential<'a>(authorization: Option<&'a str>, query: &'a HashMap<String, String>) -> Option<&'a str> {
+    authorization
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map(str::trim)
+        .or_else(|| query.get("token").map(String::as_str))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> AccessRules {
+        toml::from_str(r#"
+            metrics = ["scraper"]
+
+            [default]
+            publish = ["master"]
+            admin = ["operator"]
+
+            [channels.main]
+            publish = ["main-key"]
+            view = ["ticket"]
+
+            [channels.closed]
+            publish = []
+        "#).unwrap()
+    }
+
+    #[test]
+    fn publish_keys() {
+        let rules = rules();
+        assert!(rules.check("main", Role::Publisher, Some("main-key")).is_ok());
+        // a channel's own keys replace the default ones
+        assert!(matches!(rules.check("main", Role::Publisher, Some("master")), Err(AccessDenied::Forbidden)));
+        assert!(matches!(rules.check("main", Role::Publisher, None), Err(AccessDenied::Unauthorized)));
+        assert!(rules.check("other", Role::Publisher, Some("master")).is_ok());
+        assert!(matches!(rules.check("closed", Role::Publisher, Some("master")), Err(AccessDenied::Forbidden)));
+
+        // without any keys, nobody may publish
+        let empty = AccessRules::default();
+        assert!(matches!(empty.check("main", Role::Publisher, Some("master")), Err(AccessDenied::Forbidden)));
+    }
+
+    #[test]
+    fn view_tokens() {
+        let rules = rules();
+        assert!(rules.check("main", Role::Viewer, Some("ticket")).is_ok());
+        assert!(matches!(rules.check("main", Role::Viewer, None), Err(AccessDenied::Unauthorized)));
+        assert!(matches!(rules.check("main", Role::Viewer, Some("ticke")), Err(AccessDenied::Forbidden)));
+        assert!(rules.check("other", Role::Viewer, None).is_ok());
+    }
+
+    #[test]
+    fn admin_keys() {
+        let rules = rules();
+        assert!(rules.check("main", Role::Admin, Some("operator")).is_ok());
+        assert!(matches!(rules.check("main", Role::Admin, Some("master")), Err(AccessDenied::Forbidden)));
+        assert!(matches!(rules.check("main", Role::Admin, None), Err(AccessDenied::Unauthorized)));
+
+        // without any keys, nobody may administer channels
+        let empty = AccessRules::default();
+        assert!(matches!(empty.check("main", Role::Admin, Some("operator")), Err(AccessDenied::Forbidden)));
+    }
+
+    #[test]
+    fn metrics_keys() {
+        let rules = rules();
+        assert!(rules.check_metrics(Some("scraper")).is_ok());
+        assert!(matches!(rules.check_metrics(Some("operator")), Err(AccessDenied::Forbidden)));
+        assert!(matches!(rules.check_metrics(None), Err(AccessDenied::Unauthorized)));
+
+        // without any keys, nobody may read the metrics
+        let empty = AccessRules::default();
+        assert!(matches!(empty.check_metrics(Some("scraper")), Err(AccessDenied::Forbidden)));
+    }
+
+    #[test]
+    fn credential_sources() {
+        let mut query = HashMap::new();
+        assert_eq!(credential(Some("Bearer abc"), &query), Some("abc"));
+        assert_eq!(credential(Some("Basic abc"), &query), None);
+        query.insert("token".to_string(), "xyz".to_string());
+        assert_eq!(credential(None, &query), Some("xyz"));
+        assert_eq!(credential(Some("Bearer abc"), &query), Some("abc"));
+    }
+
+    #[test]
+    fn unknown_fields_rejected() {
+        assert!(toml::from_str::<AccessRules>("[channels.main]\npublsh = [\"typo\"]").is_err());
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{de::Error, Deserialize, Deserializer};
+use webmetro::{
+    channel::{BufferLimits, ChannelSettings, QueueLimit, SlowListenerPolicy, TransmitterPolicy},
+    error::WebmetroError,
+    recording::RecordingSettings,
+};
+
+use super::{auth::AccessRules, parse_time};
+
+/// Reads a number of seconds, given either as a number or a string like `"30s"`
+fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Seconds {
+        Number(u64),
+        Text(String),
+    }
+
+    match Seconds::deserialize(deserializer)? {
+        Seconds::Number(secs) => Ok(Some(Duration::from_secs(secs))),
+        Seconds::Text(text) => parse_time(&text).map(Some).map_err(D::Error::custom),
+    }
+}
+
+/// Reads a string using the type's `FromStr` implementation
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?.parse().map(Some).map_err(D::Error::custom)
+}
+
+/// How much of a source's stream the relay may buffer at once while parsing it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SourceLimits {
+    /// the largest EBML element accepted
+    pub parser_bytes: usize,
+    /// the largest initialization segment or cluster accepted
+    pub chunk_bytes: usize,
+}
+
+pub const DEFAULT_SOURCE_BUFFER_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Channel settings from a config file section; anything left out falls back to the
+/// `[default]` section, and then to the same defaults as the command line options
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ChannelConfig {
+    #[serde(default, deserialize_with = "seconds")]
+    pub buffer_duration: Option<Duration>,
+    pub buffer_bytes: Option<usize>,
+    pub gop_cache_bytes: Option<usize>,
+    pub listener_queue_clusters: Option<usize>,
+    pub listener_queue_bytes: Option<usize>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub slow_listener_policy: Option<SlowListenerPolicy>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub transmitter_policy: Option<TransmitterPolicy>,
+    #[serde(default, deserialize_with = "seconds")]
+    pub failover_timeout: Option<Duration>,
+    pub parser_buffer_limit: Option<usize>,
+    pub chunk_buffer_limit: Option<usize>,
+    /// record each source to files in this directory
+    pub record_dir: Option<PathBuf>,
+    pub record_max_bytes: Option<u64>,
+    #[serde(default, deserialize_with = "seconds")]
+    pub record_max_duration: Option<Duration>,
+}
+
+impl ChannelConfig {
+    /// Fill in whatever this section leaves out from another one
+    fn or(&self, fallback: &ChannelConfig) -> ChannelConfig {
+        // the two queue limits are alternatives, so they're inherited together
+        let queue = match (self.listener_queue_clusters, self.listener_queue_bytes) {
+            (None, None) => fallback,
+            _ => self,
+        };
+        ChannelConfig {
+            buffer_duration: self.buffer_duration.or(fallback.buffer_duration),
+            buffer_bytes: self.buffer_bytes.or(fallback.buffer_bytes),
+            gop_cache_bytes: self.gop_cache_bytes.or(fallback.gop_cache_bytes),
+            listener_queue_clusters: queue.listener_queue_clusters,
+            listener_queue_bytes: queue.listener_queue_bytes,
+            slow_listener_policy: self.slow_listener_policy.or(fallback.slow_listener_policy),
+            transmitter_policy: self.transmitter_policy.or(fallback.transmitter_policy),
+            failover_timeout: self.failover_timeout.or(fallback.failover_timeout),
+            parser_buffer_limit: self.parser_buffer_limit.or(fallback.parser_buffer_limit),
+            chunk_buffer_limit: self.chunk_buffer_limit.or(fallback.chunk_buffer_limit),
+            record_dir: self.record_dir.clone().or_else(|| fallback.record_dir.clone()),
+            record_max_bytes: self.record_max_bytes.or(fallback.record_max_bytes),
+            record_max_duration: self.record_max_duration.or(fallback.record_max_duration),
+        }
+    }
+
+    fn settings(&self) -> ChannelSettings {
+        let defaults = ChannelSettings::default();
+        ChannelSettings {
+            buffer: BufferLimits {
+                duration: self.buffer_duration.unwrap_or(defaults.buffer.duration),
+                bytes: self.buffer_bytes.unwrap_or(defaults.buffer.bytes),
+            },
+            gop_cache_bytes: self.gop_cache_bytes.unwrap_or(defaults.gop_cache_bytes),
+            listener_queue: match (self.listener_queue_clusters, self.listener_queue_bytes) {
+                (_, Some(bytes)) => QueueLimit::Bytes(bytes),
+                (Some(clusters), None) => QueueLimit::Clusters(clusters),
+                (None, None) => defaults.listener_queue,
+            },
+            slow_listener_policy: self.slow_listener_policy.unwrap_or(defaults.slow_listener_policy),
+            transmitter_policy: self.transmitter_policy.unwrap_or(defaults.transmitter_policy),
+            failover_timeout: self.failover_timeout.unwrap_or(defaults.failover_timeout),
+        }
+    }
+
+    fn source_limits(&self) -> SourceLimits {
+        SourceLimits {
+            parser_bytes: self.parser_buffer_limit.unwrap_or(DEFAULT_SOURCE_BUFFER_LIMIT),
+            chunk_bytes: self.chunk_buffer_limit.unwrap_or(DEFAULT_SOURCE_BUFFER_LIMIT),
+        }
+    }
+
+    fn recording(&self) -> Option<RecordingSettings> {
+        Some(RecordingSettings {
+            dir: self.record_dir.clone()?,
+            max_bytes: self.record_max_bytes,
+            max_duration: self.record_max_duration,
+        })
+    }
+
+    fn validate(&self, section: &str) -> Result<(), String> {
+        if self.listener_queue_clusters.is_some() && self.listener_queue_bytes.is_some() {
+            return Err(format!(
+                "[{}]: only one of listener-queue-clusters and listener-queue-bytes may be set",
+                section
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Everything the relay can be configured with, read from a TOML file, e.g.:
+///
+/// ```toml
+/// listen = ["0.0.0.0:8080"]
+/// log = "info"
+/// cors-origins = ["https://example.com"]
+///
+/// [default]
+/// buffer-duration = "60s"
+///
+/// [channels.main]
+/// transmitter-policy = "standby"
+///
+/// [auth.default]
+/// publish = ["a publish key"]
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RelayConfig {
+    /// address:port pairs to listen to
+    pub listen: Vec<String>,
+    /// the default log filter, in `RUST_LOG` syntax (the environment variable takes precedence)
+    pub log: Option<String>,
+    /// refuse channels that don't have a `[channels]` section
+    #[serde(default)]
+    pub listed_channels_only: bool,
+    pub slate: Option<PathBuf>,
+    pub tls: Option<TlsPaths>,
+    pub auth: Option<AccessRules>,
+    /// origins whose pages may fetch streams & statuses; "*" allows any
+    #[serde(default)]
+    pub cors_origins: Vec<String>,
+    /// serve a simple player page for each channel at /watch/{name}
+    #[serde(default)]
+    pub watch_page: bool,
+    #[serde(default)]
+    pub default: ChannelConfig,
+    #[serde(default)]
+    pub channels: HashMap<String, ChannelConfig>,
+}
+
+impl RelayConfig {
+    /// Read & check a config file; relative paths in it are relative to the file's directory
+    pub fn load(path: &Path) -> Result<RelayConfig, WebmetroError> {
+        let error = |message: String| WebmetroError::ApplicationError {
+            message: format!("{}: {}", path.display(), message),
+        };
+        let text = std::fs::read_to_string(path).map_err(|err| error(err.to_string()))?;
+        let mut config: RelayConfig = toml::from_str(&text).map_err(|err| error(err.to_string()))?;
+        config.validate().map_err(error)?;
+
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        config.slate = config.slate.map(|slate| base.join(slate));
+        if let Some(ref mut tls) = config.tls {
+            tls.cert = base.join(&tls.cert);
+            tls.key = base.join(&tls.key);
+        }
+        for channel in Some(&mut config.default).into_iter().chain(config.channels.values_mut()) {
+            channel.record_dir = channel.record_dir.as_ref().map(|dir| base.join(dir));
+        }
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.listen.is_empty() {
+            return Err("listen: at least one address is needed".into());
+        }
+        self.default.validate("default")?;
+        for (name, channel) in self.channels.iter() {
+            channel.validate(&format!("channels.{}", name))?;
+        }
+        Ok(())
+    }
+
+    /// Whether the relay should open the named channel
+    pub fn serves(&self, channel: &str) -> bool {
+        !self.listed_channels_only || self.channels.contains_key(channel)
+    }
+
+    /// The Access-Control-Allow-Origin to answer a request from the given origin with, if it's allowed
+    pub fn cors_origin<'a>(&'a self, origin: &'a str) -> Option<&'a str> {
+        if self.cors_origins.iter().any(|allowed| allowed == "*") {
+            Some("*")
+        } else if self.cors_origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)) {
+            Some(origin)
+        } else {
+            None
+        }
+    }
+
+    pub fn channel_settings(&self, channel: &str) -> ChannelSettings {
+        match self.channels.get(channel) {
+            Some(config) => config.or(&self.default).settings(),
+            None => self.default.settings(),
+        }
+    }
+
+    pub fn source_limits(&self, channel: &str) -> SourceLimits {
+        match self.channels.get(channel) {
+            Some(config) => config.or(&self.default).source_limits(),
+            None => self.default.source_limits(),
+        }
+    }
+
+    /// Where to record the channel's sources, if anywhere
+    pub fn recording(&self, channel: &str) -> Option<RecordingSettings> {
+        match self.channels.get(channel) {
+            Some(config) => config.or(&self.default).recording(),
+            None => self.default.recording(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::{FutureExt, StreamExt};
+    use webmetro::{
+        channel::{Channel, Listener, Transmitter},
+        chunk::{Chunk, ClusterHead},
+    };
+
+    use super::*;
+
+    fn config(text: &str) -> Result<RelayConfig, String> {
+        let config: RelayConfig = toml::from_str(text).map_err(|err| err.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn channel_settings_fall_back() {
+        let config = config(r#"
+            listen = ["localhost:8080"]
+
+            [default]
+            buffer-duration = "60s"
+            listener-queue-bytes = 100000
+            slow-listener-policy = "skip-to-keyframe"
+
+            parser-buffer-limit = 4000000
+
+            [channels.main]
+            buffer-duration = 120
+            listener-queue-clusters = 10
+            transmitter-policy = "standby"
+            chunk-buffer-limit = 8000000
+        "#).unwrap();
+
+        let main = config.channel_settings("main");
+        assert_eq!(main.buffer.duration, Duration::from_secs(120));
+        assert_eq!(main.listener_queue, QueueLimit::Clusters(10));
+        assert_eq!(main.slow_listener_policy, SlowListenerPolicy::SkipToKeyframe);
+        assert_eq!(main.transmitter_policy, TransmitterPolicy::Standby);
+        assert_eq!(config.source_limits("main"), SourceLimits {
+            parser_bytes: 4000000,
+            chunk_bytes: 8000000,
+        });
+
+        let other = config.channel_settings("other");
+        assert_eq!(other.buffer.duration, Duration::from_secs(60));
+        assert_eq!(other.listener_queue, QueueLimit::Bytes(100000));
+        assert_eq!(other.transmitter_policy, TransmitterPolicy::Takeover);
+        assert_eq!(other.failover_timeout, ChannelSettings::default().failover_timeout);
+        assert_eq!(config.source_limits("other").chunk_bytes, DEFAULT_SOURCE_BUFFER_LIMIT);
+        assert!(config.serves("other"));
+    }
+
+    #[test]
+    fn buffer_duration_alone_buffers() {
+        let config = config(r#"
+            listen = ["localhost:8080"]
+            [channels.main]
+            buffer-duration = "300s"
+        "#).unwrap();
+        let channel = Channel::with_settings("main".into(), config.channel_settings("main"));
+        let transmitter = Transmitter::new(channel.clone()).unwrap();
+        transmitter.send(Chunk::Headers { bytes: Bytes::from_static(b"header") }).unwrap();
+        for second in 0..3 {
+            let mut head = ClusterHead::new(second * 1000);
+            head.keyframe = true;
+            transmitter.send(Chunk::Cluster(head, Bytes::new())).unwrap();
+        }
+
+        // the header, then every cluster sent so far
+        let mut listener = Listener::with_offset(channel, Duration::from_secs(60));
+        let mut replayed = 0;
+        while let Some(Some(_)) = listener.next().now_or_never() {
+            replayed += 1;
+        }
+        assert_eq!(replayed, 4);
+    }
+
+    #[test]
+    fn listed_channels_only() {
+        let config = config(r#"
+            listen = ["localhost:8080"]
+            listed-channels-only = true
+            [channels.main]
+        "#).unwrap();
+        assert!(config.serves("main"));
+        assert!(!config.serves("other"));
+    }
+
+    #[test]
+    fn cors_origins() {
+        let listed = config(r#"
+            listen = ["localhost:8080"]
+            cors-origins = ["https://example.com"]
+        "#).unwrap();
+        assert_eq!(listed.cors_origin("https://example.com"), Some("https://example.com"));
+        assert_eq!(listed.cors_origin("https://example.org"), None);
+
+        let any = config(r#"
+            listen = ["localhost:8080"]
+            cors-origins = ["*"]
+        "#).unwrap();
+        assert_eq!(any.cors_origin("https://example.org"), Some("*"));
+        assert_eq!(RelayConfig::default().cors_origin("https://example.org"), None);
+    }
+
+    #[test]
+    fn invalid_configs_rejected() {
+        assert!(config("").unwrap_err().contains("listen"));
+        assert!(config("listen = []").unwrap_err().contains("listen"));
+        assert!(config("listen = [\"a:1\"]\nbuffer = 5").is_err());
+        assert!(config("listen = [\"a:1\"]\n[default]\nslow-listener-policy = \"wait\"")
+            .unwrap_err()
+            .contains("unknown slow listener policy"));
+        assert!(config("listen = [\"a:1\"]\n[channels.main]\nlistener-queue-clusters = 1\nlistener-queue-bytes = 1")
+            .unwrap_err()
+            .contains("[channels.main]"));
+        assert!(config("listen = [\"a:1\"]\n[auth.channels.main]\npublsh = [\"typo\"]").is_err());
+    }
+
+    #[test]
+    fn paths_relative_to_file() {
+        let dir = std::env::temp_dir().join(format!("webmetro-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("relay.toml");
+        std::fs::write(&path, r#"
+            listen = ["localhost:8080"]
+            slate = "brb.webm"
+            tls = { cert = "cert.pem", key = "/etc/key.pem" }
+
+            [default]
+            record-dir = "recordings"
+            record-max-bytes = 1000000
+
+            [channels.main]
+            record-max-duration = 3600
+
+            [channels.other]
+            record-dir = "/srv/other"
+        "#).unwrap();
+
+        let config = RelayConfig::load(&path).unwrap();
+        assert_eq!(config.slate, Some(dir.join("brb.webm")));
+        assert_eq!(config.tls, Some(TlsPaths {
+            cert: dir.join("cert.pem"),
+            key: "/etc/key.pem".into(),
+        }));
+        assert_eq!(config.recording("main"), Some(RecordingSettings {
+            dir: dir.join("recordings"),
+            max_bytes: Some(1000000),
+            max_duration: Some(Duration::from_secs(3600)),
+        }));
+        assert_eq!(config.recording("other").unwrap().dir, PathBuf::from("/srv/other"));
+        assert_eq!(RelayConfig::default().recording("main"), None);
+
+        std::fs::write(&path, "listen = 8080").unwrap();
+        assert!(RelayConfig::load(&path).unwrap_err().to_string().starts_with(&path.display().to_string()));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
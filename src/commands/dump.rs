@@ -3,8 +3,10 @@ use clap::Args;
 use super::stdin_stream;
 use webmetro::{
     error::WebmetroError,
+    info::parse_info,
     stream_parser::StreamEbml,
-    webm::{SimpleBlock, WebmElement::*},
+    tracks::parse_tracks,
+    webm::{parse_block_group, SimpleBlock, WebmElement::*},
 };
 
 /// Dumps WebM parsing events from parsing stdin
@@ -18,8 +20,18 @@ pub async fn run(_args: DumpArgs) -> Result<(), WebmetroError> {
     while let Some(element) = events.next().await? {
         match element {
             // suppress printing byte arrays
-            Tracks(slice) => println!("Tracks[{}]", slice.len()),
+            Info(slice) => println!("{:?}", parse_info(slice)?),
+            Tracks(slice) => {
+                println!("Tracks[{}]", slice.len());
+                match parse_tracks(slice) {
+                    Ok(tracks) => for track in tracks {
+                        println!("  Track {}: {:?} {}", track.number, track.track_type, track.codec_id);
+                    },
+                    Err(err) => println!("  (couldn't parse tracks: {})", err),
+                }
+            }
             SimpleBlock(SimpleBlock { timecode, .. }) => println!("SimpleBlock@{}", timecode),
+            BlockGroup(slice) => println!("BlockGroup@{}", parse_block_group(slice)?.block.timecode),
             other => println!("{:?}", other),
         }
     }
@@ -27,10 +27,10 @@ pub struct FilterArgs {
 
 #[tokio::main]
 pub async fn run(args: FilterArgs) -> Result<(), WebmetroError> {
-    let start_time = args.skip.map_or(0, |s| s.as_millis());
+    let start_time = args.skip.map_or(0, |s| s.as_nanos());
     let stop_time = args
         .take
-        .map_or(std::u128::MAX, |t| t.as_millis() + start_time);
+        .map_or(u128::MAX, |t| t.as_nanos() + start_time);
 
     let mut timecode_fixer = ChunkTimecodeFixer::new();
     let mut chunk_stream: Pin<Box<dyn Stream<Item = Result<Chunk, WebmetroError>> + Send>> =
@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::sync::Mutex;
+
+use webmetro::{
+    channel::{ChannelCounters, ChannelStatus, CLUSTER_DURATION_BUCKETS},
+    error::WebmetroError,
+};
+
+/// Relay-wide counts of sources failing, which outlive the channels they happened on
+#[derive(Default)]
+pub struct Metrics {
+    /// by `EbmlError` variant
+    parse_errors: Mutex<BTreeMap<&'static str, u64>>,
+    /// by which buffer limit was exceeded
+    resources_exceeded: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// Escape a label value for the Prometheus text format
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn channel_family<T: Display>(
+    out: &mut String,
+    channels: &[(ChannelStatus, ChannelCounters)],
+    (name, kind, help): (&str, &str, &str),
+    value: impl Fn(&ChannelStatus, &ChannelCounters) -> T,
+) {
+    family(out, name, kind, help);
+    for (status, counters) in channels {
+        writeln!(out, "{}{{channel=\"{}\"}} {}", name, escape(&status.name), value(status, counters)).unwrap();
+    }
+}
+
+fn counts_family(out: &mut String, counts: &Mutex<BTreeMap<&'static str, u64>>, (name, label, help): (&str, &str, &str)) {
+    family(out, name, "counter", help);
+    for (key, count) in counts.lock().expect("Locking metrics").iter() {
+        writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, key, count).unwrap();
+    }
+}
+
+impl Metrics {
+    /// Count a source's stream failing, if it failed in a way that's tracked
+    pub fn record_source_error(&self, err: &WebmetroError) {
+        let (counts, key) = match err {
+            WebmetroError::EbmlError { source } => (&self.parse_errors, source.name()),
+            WebmetroError::ResourcesExceeded { buffer, .. } => (&self.resources_exceeded, *buffer),
+            _ => return,
+        };
+        *counts.lock().expect("Locking metrics").entry(key).or_insert(0) += 1;
+    }
+
+    /// Render these & the given channels' metrics in the Prometheus text format
+    pub fn render(&self, channels: &[(ChannelStatus, ChannelCounters)]) -> String {
+        let mut out = String::new();
+        channel_family(&mut out, channels, (
+            "webmetro_source_connected", "gauge", "Whether a source is being relayed on the channel",
+        ), |status, _| status.transmitter_connected as u8);
+        channel_family(&mut out, channels, (
+            "webmetro_received_bytes_total", "counter", "Bytes of media received from sources since the channel opened",
+        ), |_, counters| counters.bytes_in);
+        channel_family(&mut out, channels, (
+            "webmetro_sent_bytes_total", "counter", "Bytes of media queued for listeners since the channel opened",
+        ), |_, counters| counters.bytes_out);
+        channel_family(&mut out, channels, (
+            "webmetro_listeners", "gauge", "Listeners connected to the channel",
+        ), |status, _| status.listeners);
+        channel_family(&mut out, channels, (
+            "webmetro_listeners_dropped_total", "counter", "Listeners disconnected for falling behind since the channel opened",
+        ), |_, counters| counters.listeners_dropped);
+
+        let name = "webmetro_cluster_duration_seconds";
+        family(&mut out, name, "histogram", "Durations of the clusters relayed since the channel opened");
+        for (status, counters) in channels {
+            let channel = escape(&status.name);
+            for (bound, count) in CLUSTER_DURATION_BUCKETS.iter().zip(counters.cluster_durations.iter()) {
+                writeln!(out, "{}_bucket{{channel=\"{}\",le=\"{}\"}} {}", name, channel, bound.as_secs_f64(), count).unwrap();
+            }
+            writeln!(out, "{}_bucket{{channel=\"{}\",le=\"+Inf\"}} {}", name, channel, counters.clusters).unwrap();
+            writeln!(out, "{}_sum{{channel=\"{}\"}} {}", name, channel, counters.cluster_duration_sum.as_secs_f64()).unwrap();
+            writeln!(out, "{}_count{{channel=\"{}\"}} {}", name, channel, counters.clusters).unwrap();
+        }
+
+        counts_family(&mut out, &self.parse_errors, (
+            "webmetro_source_parse_errors_total", "error", "Sources dropped for sending invalid EBML, by error",
+        ));
+        counts_family(&mut out, &self.resources_exceeded, (
+            "webmetro_resources_exceeded_total", "buffer", "Sources dropped for exceeding a buffer limit, by buffer",
+        ));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use webmetro::{
+        channel::{Channel, Transmitter},
+        chunk::{Chunk, ClusterHead},
+        ebml::EbmlError,
+    };
+
+    use super::*;
+
+    #[test]
+    fn renders_metrics() {
+        let channel = Channel::new("main \"1\"".into());
+        let transmitter = Transmitter::new(channel.clone()).unwrap();
+        transmitter.send(Chunk::Headers { bytes: Bytes::from_static(b"header") }).unwrap();
+        let mut head = ClusterHead::new(0);
+        head.keyframe = true;
+        head.observe_simpleblock_timecode(1500);
+        transmitter.send(Chunk::Cluster(head, Bytes::new())).unwrap();
+
+        let metrics = Metrics::default();
+        metrics.record_source_error(&WebmetroError::EbmlError { source: EbmlError::CorruptVarint });
+        metrics.record_source_error(&WebmetroError::EbmlError { source: EbmlError::CorruptVarint });
+        metrics.record_source_error(&WebmetroError::ResourcesExceeded { buffer: "chunk", size: 2, limit: 1 });
+        metrics.record_source_error(&"not counted".into());
+
+        let channels = {
+            let channel = channel.lock().unwrap();
+            vec![(channel.status(), channel.counters().clone())]
+        };
+        let text = metrics.render(&channels);
+        let lines: Vec<&str> = text.lines().collect();
+        for expected in [
+            "# TYPE webmetro_source_connected gauge",
+            "webmetro_source_connected{channel=\"main \\\"1\\\"\"} 1",
+            "webmetro_listeners{channel=\"main \\\"1\\\"\"} 0",
+            "webmetro_cluster_duration_seconds_bucket{channel=\"main \\\"1\\\"\",le=\"1\"} 0",
+            "webmetro_cluster_duration_seconds_bucket{channel=\"main \\\"1\\\"\",le=\"2\"} 1",
+            "webmetro_cluster_duration_seconds_bucket{channel=\"main \\\"1\\\"\",le=\"+Inf\"} 1",
+            "webmetro_cluster_duration_seconds_sum{channel=\"main \\\"1\\\"\"} 1.5",
+            "webmetro_source_parse_errors_total{error=\"CorruptVarint\"} 2",
+            "webmetro_resources_exceeded_total{buffer=\"chunk\"} 1",
+        ] {
+            assert!(lines.contains(&expected), "missing {:?} in:\n{}", expected, text);
+        }
+    }
+}
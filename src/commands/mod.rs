@@ -5,10 +5,14 @@ use futures::{Stream, TryStreamExt};
 use tokio_util::codec::{BytesCodec, FramedRead};
 use webmetro::error::WebmetroError;
 
+pub mod auth;
+pub mod config;
 pub mod dump;
 pub mod filter;
+pub mod metrics;
 pub mod relay;
 pub mod send;
+pub mod tls;
 
 /// An adapter that makes chunks of bytes from stdin available as a Stream;
 /// is NOT actually async, and just uses blocking read. Don't use more than
@@ -17,8 +21,9 @@ pub fn stdin_stream() -> impl Stream<Item = Result<Bytes, std::io::Error>> + Siz
     FramedRead::new(tokio::io::stdin(), BytesCodec::new()).map_ok(|bytes| bytes.freeze())
 }
 
+/// Parses a whole number of seconds, optionally suffixed with "s" (e.g., `30` or `30s`)
 pub fn parse_time(arg: &str) -> Result<Duration, WebmetroError> {
-    match arg.parse() {
+    match arg.strip_suffix('s').unwrap_or(arg).parse() {
         Ok(secs) => Ok(Duration::from_secs(secs)),
         Err(err) => Err(WebmetroError::ApplicationError {
             message: err.to_string(),
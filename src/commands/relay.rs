@@ -1,53 +1,312 @@
-use std::net::ToSocketAddrs;
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
 use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use bytes::{Buf, Bytes};
 use clap::Args;
-use futures::{prelude::*, stream::FuturesUnordered, Stream};
+use futures::{future::Either, prelude::*, stream::FuturesUnordered, Stream};
 use hyper::{
-    header::{CACHE_CONTROL, CONTENT_TYPE},
-    Body, Response,
+    header::{
+        HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+        ACCESS_CONTROL_MAX_AGE, CACHE_CONTROL, CONTENT_TYPE, VARY, WWW_AUTHENTICATE,
+    },
+    Body, Method, Response, StatusCode,
 };
 use stream::iter;
-use warp::{self, path, Filter};
+use tokio::net::TcpListener;
+use warp::{
+    self, path,
+    ws::{Message, WebSocket, Ws},
+    Filter, Rejection, Reply,
+};
 use weak_table::WeakValueHashMap;
+use super::{
+    auth::{credential, AccessDenied, AccessRules, Role},
+    config::{ChannelConfig, RelayConfig, SourceLimits, TlsPaths, DEFAULT_SOURCE_BUFFER_LIMIT},
+    metrics::Metrics,
+    parse_time,
+    tls::{serve_tls, TlsConfig},
+};
 use webmetro::{
-    channel::{Channel, Handle, Listener, Transmitter},
+    channel::{
+        Channel, ChannelCounters, ChannelError, ChannelStatus, Handle, Listener, SlowListenerPolicy, Transmitter, TransmitterPolicy,
+        DEFAULT_GOP_CACHE_BYTES,
+    },
     chunk::Chunk,
     chunk::WebmStream,
+    dash::DashPackager,
     error::WebmetroError,
     fixers::{ChunkStream, ChunkTimecodeFixer},
+    recording::{Recorder, RecordingSettings},
     stream_parser::StreamEbml,
 };
 
-const BUFFER_LIMIT: usize = 2 * 1024 * 1024;
-
-fn get_stream(channel: Handle) -> impl Stream<Item = Result<Bytes, WebmetroError>> {
+/// A channel's chunks for a new listener, from a starting point & with continuous timecodes
+fn listen_chunks(channel: Handle, offset: Duration) -> impl Stream<Item = Result<Chunk, WebmetroError>> {
     let mut timecode_fixer = ChunkTimecodeFixer::new();
-    Listener::new(channel)
-        .map(|c| Result::<Chunk, WebmetroError>::Ok(c))
+    Listener::with_offset(channel, offset)
+        .map(Result::<Chunk, WebmetroError>::Ok)
         .map_ok(move |chunk| timecode_fixer.process(chunk))
         .find_starting_point()
+}
+
+fn get_stream(channel: Handle, offset: Duration) -> impl Stream<Item = Result<Bytes, WebmetroError>> {
+    listen_chunks(channel, offset)
         .map_ok(|webm_chunk| iter(webm_chunk).map(Result::<Bytes, WebmetroError>::Ok))
         .try_flatten()
 }
 
+/// Send a channel to a WebSocket client as one binary message per chunk
+async fn websocket_stream(socket: WebSocket, channel: Handle, offset: Duration) {
+    let (mut sink, incoming) = socket.split();
+    let mut chunks = Box::pin(listen_chunks(channel, offset));
+    let send = async move {
+        while let Some(Ok(chunk)) = chunks.next().await {
+            let mut message = Vec::with_capacity(chunk.byte_size());
+            for bytes in chunk {
+                message.extend_from_slice(&bytes);
+            }
+            if sink.send(Message::binary(message)).await.is_err() {
+                return;
+            }
+        }
+        // the listener was disconnected by the channel
+        sink.close().await.ok();
+    };
+    // reading what the client sends (which is ignored) answers pings and notices it leaving
+    let receive = incoming.try_for_each(|_| future::ready(Ok(())));
+    future::select(Box::pin(send), receive).await;
+}
+
+/// Relay a source's WebSocket binary messages into the channel, as one continuous WebM stream
+async fn websocket_source(
+    socket: WebSocket,
+    transmitter: Transmitter,
+    limits: SourceLimits,
+    recorder: Option<SyncSender<Chunk>>,
+    metrics: Arc<Metrics>,
+) {
+    let (mut sink, incoming) = socket.split();
+    let media = Box::pin(
+        incoming
+            .try_take_while(|message| future::ready(Ok(!message.is_close())))
+            .try_filter(|message| future::ready(message.is_binary()))
+            .map_ok(|message| Bytes::from(message.into_bytes())),
+    );
+    let mut replies = Box::pin(post_stream(transmitter, limits, recorder, metrics, media));
+    while let Some(Ok(reply)) = replies.next().await {
+        // tell the source why it's being cut off, if it is
+        if !reply.is_empty() {
+            let reason = String::from_utf8_lossy(&reply).trim_end().to_string();
+            sink.send(Message::text(reason)).await.ok();
+        }
+    }
+    sink.close().await.ok();
+}
+
+/// The ?offset= a listener asked to start at, or why it's invalid
+fn requested_offset(query: &HashMap<String, String>, name: &str) -> Result<Duration, String> {
+    match query.get("offset").map(|offset| parse_time(offset)) {
+        None => Ok(Duration::ZERO),
+        Some(Ok(offset)) => Ok(offset),
+        Some(Err(err)) => {
+            warn!("Bad offset requested on Channel {}: {}", name, err);
+            Err(err.to_string())
+        }
+    }
+}
+
 fn post_stream(
-    channel: Handle,
+    channel: Transmitter,
+    limits: SourceLimits,
+    recorder: Option<SyncSender<Chunk>>,
+    metrics: Arc<Metrics>,
     stream: impl Stream<Item = Result<impl Buf, warp::Error>> + Unpin,
 ) -> impl Stream<Item = Result<Bytes, WebmetroError>> {
-    let channel = Transmitter::new(channel);
-    stream
+    let kicked = channel.kicked();
+    let chunks = stream
         .map_err(WebmetroError::from)
         .parse_ebml()
-        .with_soft_limit(BUFFER_LIMIT)
+        .with_soft_limit(limits.parser_bytes)
         .chunk_webm()
-        .with_soft_limit(BUFFER_LIMIT)
-        .map_ok(move |chunk| {
-            channel.send(chunk);
-            Bytes::new()
+        .with_soft_limit(limits.chunk_bytes)
+        .and_then(move |chunk| {
+            // a source's header is needed to record it once it's relayed, but its clusters
+            // are only recorded while it is (not while it's on standby or a backup)
+            let is_header = matches!(chunk, Chunk::Headers { .. });
+            let recorded = recorder.as_ref().map(|recorder| (recorder, chunk.clone()));
+            future::ready(match channel.send(chunk) {
+                Ok(relayed) => {
+                    if let Some((recorder, chunk)) = recorded.filter(|_| relayed || is_header) {
+                        if let Err(TrySendError::Full(_)) = recorder.try_send(chunk) {
+                            warn!("Recording is falling behind the source; dropped a chunk");
+                        }
+                    }
+                    Ok(Bytes::new())
+                }
+                Err(err) => Err(err.into()),
+            })
+        });
+
+    // on failure, tell the source why it's being cut off instead of just dropping the
+    // connection, and stop there (the parser would just repeat its error); a kicked
+    // source is cut off even if it's not sending anything
+    stream::unfold(Some((chunks, kicked)), move |state| {
+        let metrics = metrics.clone();
+        async move {
+            let (mut chunks, mut kicked) = state?;
+            let next = match future::select(chunks.next(), &mut kicked).await {
+                Either::Left((next, _)) => next?,
+                Either::Right(((), _)) => Err(ChannelError::Kicked.into()),
+            };
+            match next {
+                Ok(bytes) => Some((Ok(bytes), Some((chunks, kicked)))),
+                Err(err) => {
+                    warn!("{}", err);
+                    metrics.record_source_error(&err);
+                    Some((Ok(Bytes::from(format!("{}\n", err))), None))
+                }
+            }
+        }
+    })
+}
+
+/// How many chunks may wait to be recorded before further ones are dropped
+const RECORDING_QUEUE_CHUNKS: usize = 64;
+
+/// Record a source's chunks on a thread of its own, so a slow disk doesn't hold up the relay
+/// (chunks it can't keep up with are dropped instead of piling up in memory); the last file
+/// is finished once the source disconnects & the sender is dropped
+fn start_recording(name: &str, settings: RecordingSettings) -> SyncSender<Chunk> {
+    let (sender, receiver) = sync_channel(RECORDING_QUEUE_CHUNKS);
+    let mut recorder = Recorder::new(name, settings);
+    let name = name.to_string();
+    std::thread::spawn(move || {
+        for chunk in receiver {
+            if let Err(err) = recorder.push(chunk) {
+                error!("Stopped recording Channel {}: {}", name, err);
+                break;
+            }
+        }
+        if let Err(err) = recorder.finish() {
+            error!("Couldn't finish recording Channel {}: {}", name, err);
+        }
+    });
+    sender
+}
+
+/// Read a WebM file into chunks for looping into channels with no source
+async fn load_slate(path: &Path) -> Result<Arc<Vec<Chunk>>, WebmetroError> {
+    let bytes = Bytes::from(std::fs::read(path)?);
+    let chunks: Vec<Chunk> = iter(Some(Result::<Bytes, WebmetroError>::Ok(bytes)))
+        .parse_ebml()
+        .chunk_webm()
+        .try_collect()
+        .await?;
+    if !chunks.iter().any(Chunk::is_keyframe) {
+        return Err("Slate file has no keyframes to start from".into());
+    }
+    Ok(Arc::new(chunks))
+}
+
+/// How long a channel keeps being packaged for DASH after the last request for it
+const DASH_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A channel being packaged for DASH players
+struct DashSession {
+    /// part of the segment URLs, so caches never confuse them with an earlier session's
+    id: u128,
+    packager: Mutex<DashPackager>,
+    last_request: Mutex<Instant>,
+}
+
+impl DashSession {
+    fn touch(&self) {
+        *self.last_request.lock().expect("Locking DASH session") = Instant::now();
+    }
+
+    fn idle(&self) -> bool {
+        self.last_request.lock().expect("Locking DASH session").elapsed() > DASH_IDLE_TIMEOUT
+    }
+}
+
+/// Percent-encode a query parameter value
+fn query_escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
         })
-        .inspect_err(|err| warn!("{}", err))
+        .collect()
+}
+
+/// Loop the slate into a channel in real time whenever it has no source, for as long as the channel exists
+fn spawn_slate(channel: &Handle, slate: Arc<Vec<Chunk>>) {
+    let channel = Arc::downgrade(channel);
+    tokio::spawn(async move {
+        while let Some(idle) = channel.upgrade().map(|channel| Channel::idle(&channel)) {
+            idle.await;
+
+            // start the slate over, so throttling doesn't catch up on the time a source was live
+            let slate = slate.clone();
+            let mut timecode_fixer = ChunkTimecodeFixer::new();
+            let mut chunks = Box::pin(
+                iter((0..slate.len()).cycle().map(move |index| Result::<Chunk, WebmetroError>::Ok(slate[index].clone())))
+                    .map_ok(move |chunk| timecode_fixer.process(chunk))
+                    .throttle(),
+            );
+            while let Some(Ok(chunk)) = chunks.next().await {
+                let channel = match channel.upgrade() {
+                    Some(channel) => channel,
+                    None => return,
+                };
+                let mut channel = channel.lock().expect("Locking channel");
+                if channel.has_source() {
+                    break;
+                }
+                channel.send_slate(chunk);
+            }
+        }
+    });
+}
+
+/// Check a client's credential against the access rules (if any are configured),
+/// logging & building the response if the client is rejected
+fn rejection(
+    access: &Option<AccessRules>,
+    name: &str,
+    role: Role,
+    authorization: Option<String>,
+    query: &HashMap<String, String>,
+    remote: Option<SocketAddr>,
+) -> Option<Response<Body>> {
+    let denial = access.as_ref()?.check(name, role, credential(authorization.as_deref(), query)).err()?;
+    Some(refusal(denial, &format!("{} on Channel {}", role, name), remote))
+}
+
+/// Log a rejected request & build its response
+fn refusal(denial: AccessDenied, request: &str, remote: Option<SocketAddr>) -> Response<Body> {
+    let remote = remote.map_or_else(|| "unknown address".to_string(), |addr| addr.to_string());
+    warn!("Rejected {} from {}: {}", request, remote, denial);
+    let response = match denial {
+        AccessDenied::Unauthorized => Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(WWW_AUTHENTICATE, "Bearer"),
+        AccessDenied::Forbidden => Response::builder().status(StatusCode::FORBIDDEN),
+    };
+    response.body(Body::from(denial.to_string())).unwrap()
+}
+
+fn no_such_channel() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::from("no such channel"))
+        .unwrap()
 }
 
 fn media_response(body: Body) -> Response<Body> {
@@ -59,61 +318,1016 @@ fn media_response(body: Body) -> Response<Body> {
         .unwrap()
 }
 
+/// A player page for /watch/{name}, which plays ../live/{name}
+const WATCH_PAGE: &str = include_str!("watch.html");
+
+/// Let pages from the request's origin read the response, if the config allows that origin
+fn allow_origin(config: &RelayConfig, origin: Option<&str>, mut response: Response<Body>) -> Response<Body> {
+    let headers = response.headers_mut();
+    headers.append(VARY, HeaderValue::from_static("Origin"));
+    if let Some(allowed) = origin.and_then(|origin| config.cors_origin(origin)) {
+        if let Ok(allowed) = HeaderValue::from_str(allowed) {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
+        }
+    }
+    response
+}
+
 /// Hosts an HTTP-based relay server
 #[derive(Args, Debug)]
 pub struct RelayArgs {
     /// The address:port to listen to
-    listen: String,
+    #[clap(required_unless_present = "config")]
+    listen: Option<String>,
+    /// Keep up to n seconds of recent video per channel, so viewers can join at the latest keyframe or request an earlier start with ?offset=
+    #[clap(long, parse(try_from_str = parse_time), default_value = "0")]
+    buffer_duration: Duration,
+    /// Keep at most this many bytes of recent video per channel (0 for no limit besides --buffer-duration's)
+    #[clap(long, default_value = "0")]
+    buffer_bytes: usize,
+    /// Even without a buffer, keep up to this many bytes of video since the latest keyframe so viewers can start instantly (0 to disable)
+    #[clap(long, default_value_t = DEFAULT_GOP_CACHE_BYTES)]
+    gop_cache_bytes: usize,
+    /// What to do when a viewer falls behind: disconnect, skip-to-keyframe (once the viewer catches up), or drop-non-keyframes
+    #[clap(long, default_value = "disconnect")]
+    slow_listener_policy: SlowListenerPolicy,
+    /// How many clusters a viewer may have queued before it counts as falling behind
+    #[clap(long, default_value = "5")]
+    listener_queue_clusters: usize,
+    /// Measure a viewer's queue in bytes instead of clusters
+    #[clap(long, conflicts_with = "listener-queue-clusters")]
+    listener_queue_bytes: Option<usize>,
+    /// What to do when a second source connects to a channel: reject it, let it take over, or keep it on standby until the current source disconnects
+    #[clap(long, default_value = "takeover")]
+    transmitter_policy: TransmitterPolicy,
+    /// Switch to a backup source (connected with ?role=backup) after the active source has sent nothing for this many seconds
+    #[clap(long, parse(try_from_str = parse_time), default_value = "5")]
+    failover_timeout: Duration,
+    /// Refuse sources with any single EBML element larger than this many bytes
+    #[clap(long, default_value_t = DEFAULT_SOURCE_BUFFER_LIMIT)]
+    parser_buffer_limit: usize,
+    /// Refuse sources with an initialization segment or cluster larger than this many bytes
+    #[clap(long, default_value_t = DEFAULT_SOURCE_BUFFER_LIMIT)]
+    chunk_buffer_limit: usize,
+    /// A WebM file to loop on channels while no source is connected (if its codecs match the last source's)
+    #[clap(long)]
+    slate: Option<PathBuf>,
+    /// Serve HTTPS using this PEM certificate chain (reloaded on SIGHUP)
+    #[clap(long, requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+    /// The PEM private key for --tls-cert
+    #[clap(long, requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+    /// A TOML file of per-channel publish keys & view tokens (reloaded on SIGHUP); without it, anyone may publish or view
+    #[clap(long)]
+    auth: Option<PathBuf>,
+    /// Let pages from this origin (or * for any) fetch streams & statuses; may be given more than once
+    #[clap(long, multiple_occurrences = true)]
+    cors_origin: Vec<String>,
+    /// Serve a simple player page for each channel at /watch/{name}
+    #[clap(long)]
+    watch_page: bool,
+    /// Record each source to files in this directory, named by channel & start time
+    #[clap(long)]
+    record_dir: Option<PathBuf>,
+    /// Start a new recording file at the next keyframe once one reaches this many bytes
+    #[clap(long, requires = "record-dir")]
+    record_max_bytes: Option<u64>,
+    /// Start a new recording file at the next keyframe once one covers this many seconds
+    #[clap(long, requires = "record-dir", parse(try_from_str = parse_time))]
+    record_max_duration: Option<Duration>,
+    /// Read all settings from a TOML config file instead of the command line (reloaded on SIGHUP)
+    #[clap(long, conflicts_with_all = &[
+        "listen", "buffer-duration", "buffer-bytes", "gop-cache-bytes", "slow-listener-policy",
+        "listener-queue-clusters", "listener-queue-bytes", "transmitter-policy", "failover-timeout",
+        "parser-buffer-limit", "chunk-buffer-limit", "slate", "tls-cert", "tls-key", "auth",
+        "cors-origin", "watch-page", "record-dir", "record-max-bytes", "record-max-duration",
+    ])]
+    config: Option<PathBuf>,
 }
 
-#[tokio::main]
-pub async fn run(args: RelayArgs) -> Result<(), WebmetroError> {
-    let channel_map = Arc::new(Mutex::new(
-        WeakValueHashMap::<String, Weak<Mutex<Channel>>>::new(),
-    ));
-    let addr_str = args.listen;
+impl RelayArgs {
+    /// The relay's configuration, from the config file if one was given or else the command line
+    fn config(&self) -> Result<RelayConfig, WebmetroError> {
+        if let Some(ref path) = self.config {
+            return RelayConfig::load(path);
+        }
+        Ok(RelayConfig {
+            listen: self.listen.iter().cloned().collect(),
+            slate: self.slate.clone(),
+            tls: match (&self.tls_cert, &self.tls_key) {
+                (Some(cert), Some(key)) => Some(TlsPaths {
+                    cert: cert.clone(),
+                    key: key.clone(),
+                }),
+                _ => None,
+            },
+            auth: match self.auth {
+                Some(ref path) => Some(AccessRules::load(path)?),
+                None => None,
+            },
+            cors_origins: self.cors_origin.clone(),
+            watch_page: self.watch_page,
+            default: ChannelConfig {
+                buffer_duration: Some(self.buffer_duration),
+                buffer_bytes: Some(self.buffer_bytes),
+                gop_cache_bytes: Some(self.gop_cache_bytes),
+                listener_queue_clusters: Some(self.listener_queue_clusters),
+                listener_queue_bytes: self.listener_queue_bytes,
+                slow_listener_policy: Some(self.slow_listener_policy),
+                transmitter_policy: Some(self.transmitter_policy),
+                failover_timeout: Some(self.failover_timeout),
+                parser_buffer_limit: Some(self.parser_buffer_limit),
+                chunk_buffer_limit: Some(self.chunk_buffer_limit),
+                record_dir: self.record_dir.clone(),
+                record_max_bytes: self.record_max_bytes,
+                record_max_duration: self.record_max_duration,
+            },
+            ..RelayConfig::default()
+        })
+    }
+}
 
-    let addrs = addr_str.to_socket_addrs()?;
-    info!("Binding to {:?}", addrs);
-    if addrs.len() == 0 {
-        return Err("Listen address didn't resolve".into());
+/// State shared by the relay's request handlers; reloading swaps in a new config
+struct RelayState {
+    config: Mutex<Arc<RelayConfig>>,
+    channels: Mutex<WeakValueHashMap<String, Weak<Mutex<Channel>>>>,
+    slate: Option<Arc<Vec<Chunk>>>,
+    metrics: Arc<Metrics>,
+    /// channels that admins have closed to new sources, until they're unlocked or the relay restarts
+    locked: Mutex<HashSet<String>>,
+    /// channels being packaged for DASH, by name
+    dash: Mutex<HashMap<String, Arc<DashSession>>>,
+}
+
+impl RelayState {
+    fn new(config: RelayConfig, slate: Option<Arc<Vec<Chunk>>>) -> Arc<RelayState> {
+        Arc::new(RelayState {
+            config: Mutex::new(Arc::new(config)),
+            channels: Mutex::new(WeakValueHashMap::new()),
+            slate,
+            metrics: Arc::new(Metrics::default()),
+            locked: Mutex::new(HashSet::new()),
+            dash: Mutex::new(HashMap::new()),
+        })
     }
 
-    let channel = path!("live" / String).map(move |name: String| {
-        let channel = channel_map
-            .lock()
-            .unwrap()
-            .entry(name.clone())
-            .or_insert_with(|| Channel::new(name.clone()));
-        (channel, name)
-    });
+    fn config(&self) -> Arc<RelayConfig> {
+        self.config.lock().expect("Locking relay config").clone()
+    }
 
-    let head = channel.clone().and(warp::head()).map(|(_, name)| {
-        info!("HEAD Request For Channel {}", name);
-        media_response(Body::empty())
-    });
+    /// Find or open the named channel, unless the config doesn't allow it
+    fn channel(&self, name: &str) -> Option<Handle> {
+        let mut channels = self.channels.lock().expect("Locking channel map");
+        let config = self.config();
+        if !config.serves(name) {
+            return None;
+        }
+        Some(channels.entry(name.to_string()).or_insert_with(|| {
+            let channel = Channel::with_settings(name.to_string(), config.channel_settings(name));
+            if let Some(ref slate) = self.slate {
+                spawn_slate(&channel, slate.clone());
+            }
+            channel
+        }))
+    }
+
+    /// The status of every open channel, by name
+    fn statuses(&self) -> Vec<ChannelStatus> {
+        let channels = self.channels.lock().expect("Locking channel map");
+        let mut statuses: Vec<ChannelStatus> = channels
+            .values()
+            .map(|channel| channel.lock().expect("Locking channel").status())
+            .collect();
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+        statuses
+    }
 
-    let get = channel.clone().and(warp::get()).map(|(channel, name)| {
-        info!("Listener Connected On Channel {}", name);
-        media_response(Body::wrap_stream(get_stream(channel)))
+    /// Prometheus metrics for the relay & every open channel
+    fn render_metrics(&self) -> String {
+        let mut channels: Vec<(ChannelStatus, ChannelCounters)> = {
+            let channels = self.channels.lock().expect("Locking channel map");
+            channels
+                .values()
+                .map(|channel| {
+                    let channel = channel.lock().expect("Locking channel");
+                    (channel.status(), channel.counters().clone())
+                })
+                .collect()
+        };
+        channels.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+        self.metrics.render(&channels)
+    }
+
+    /// The named channel, if it's already open
+    fn open_channel(&self, name: &str) -> Option<Handle> {
+        self.channels.lock().expect("Locking channel map").get(name)
+    }
+
+    /// The status of the named channel, if it's open
+    fn status(&self, name: &str) -> Option<ChannelStatus> {
+        let status = self.open_channel(name)?.lock().expect("Locking channel").status();
+        Some(status)
+    }
+
+    fn is_locked(&self, name: &str) -> bool {
+        self.locked.lock().expect("Locking locked channels").contains(name)
+    }
+
+    /// The channel's DASH session, starting one if it isn't being packaged yet
+    fn dash_session(self: &Arc<Self>, name: &str, channel: Handle) -> Arc<DashSession> {
+        let mut sessions = self.dash.lock().expect("Locking DASH sessions");
+        if let Some(session) = sessions.get(name) {
+            session.touch();
+            return session.clone();
+        }
+
+        info!("Packaging Channel {} for DASH", name);
+        let session = Arc::new(DashSession {
+            id: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis(),
+            packager: Mutex::new(DashPackager::new()),
+            last_request: Mutex::new(Instant::now()),
+        });
+        sessions.insert(name.to_string(), session.clone());
+
+        // listen to the channel until DASH players stop asking for it
+        let (state, name, task_session) = (self.clone(), name.to_string(), session.clone());
+        tokio::spawn(async move {
+            let mut listener = Listener::new(channel);
+            loop {
+                match tokio::time::timeout(DASH_IDLE_TIMEOUT, listener.next()).await {
+                    Ok(Some(chunk)) => task_session.packager.lock().expect("Locking DASH packager").push(chunk),
+                    Ok(None) => break,
+                    Err(_) => {}
+                }
+                if task_session.idle() {
+                    break;
+                }
+            }
+            let mut sessions = state.dash.lock().expect("Locking DASH sessions");
+            if matches!(sessions.get(&name), Some(current) if Arc::ptr_eq(current, &task_session)) {
+                sessions.remove(&name);
+            }
+            info!("Stopped packaging Channel {} for DASH", name);
+        });
+        session
+    }
+
+    /// Check whether a new source may publish to the named channel, opening it & connecting the source
+    /// if so, answering with `connected`'s response or why the source was refused
+    fn connect_source(
+        &self,
+        name: &str,
+        query: &HashMap<String, String>,
+        authorization: Option<String>,
+        remote: Option<SocketAddr>,
+        connected: impl FnOnce(Transmitter, SourceLimits, Option<SyncSender<Chunk>>) -> Response<Body>,
+    ) -> Response<Body> {
+        let config = self.config();
+        if let Some(response) = rejection(&config.auth, name, Role::Publisher, authorization, query, remote) {
+            return response;
+        }
+        if self.is_locked(name) {
+            warn!("Refused source on locked Channel {}", name);
+            return Response::builder()
+                .status(StatusCode::LOCKED)
+                .body(Body::from("channel is locked"))
+                .unwrap();
+        }
+        let channel = match self.channel(name) {
+            Some(channel) => channel,
+            None => return no_such_channel(),
+        };
+        let transmitter = match query.get("role").map(String::as_str) {
+            None | Some("primary") => Transmitter::new(channel),
+            Some("backup") => Transmitter::backup(channel),
+            Some(role) => {
+                warn!("Unknown source role {:?} on Channel {}", role, name);
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("role must be primary or backup"))
+                    .unwrap();
+            }
+        };
+        match transmitter {
+            Ok(transmitter) => {
+                info!("Source Connected On Channel {}", name);
+                let recorder = config.recording(name).map(|settings| start_recording(name, settings));
+                connected(transmitter, config.source_limits(name), recorder)
+            }
+            Err(err) => Response::builder()
+                .status(StatusCode::CONFLICT)
+                .body(Body::from(err.to_string()))
+                .unwrap(),
+        }
+    }
+
+    /// Carry out an admin action on the named channel, describing the outcome
+    fn administer(&self, name: &str, action: &str) -> (StatusCode, String) {
+        match action {
+            "kick-source" => match self.open_channel(name) {
+                Some(channel) if channel.lock().expect("Locking channel").kick_transmitter() => {
+                    (StatusCode::OK, "source disconnected".into())
+                }
+                _ => (StatusCode::NOT_FOUND, "no source connected".into()),
+            },
+            "kick-listeners" => {
+                let count = self
+                    .open_channel(name)
+                    .map_or(0, |channel| channel.lock().expect("Locking channel").kick_listeners());
+                (StatusCode::OK, format!("{} listeners disconnected", count))
+            }
+            "lock" => {
+                info!("Locking Channel {} against new sources", name);
+                self.locked.lock().expect("Locking locked channels").insert(name.to_string());
+                (StatusCode::OK, "channel locked".into())
+            }
+            "unlock" => {
+                info!("Unlocking Channel {}", name);
+                self.locked.lock().expect("Locking locked channels").remove(name);
+                (StatusCode::OK, "channel unlocked".into())
+            }
+            _ => (StatusCode::NOT_FOUND, "unknown admin action".into()),
+        }
+    }
+
+    /// Switch to a new config; open channels take on their new settings straight away
+    fn update(&self, config: RelayConfig) {
+        let channels = self.channels.lock().expect("Locking channel map");
+        for (name, channel) in channels.iter() {
+            channel.lock().expect("Locking channel").update_settings(config.channel_settings(name));
+        }
+        *self.config.lock().expect("Locking relay config") = Arc::new(config);
+    }
+}
+
+/// Re-read the configuration (including the TLS certificate) and apply it to the running relay
+fn reload(args: &RelayArgs, state: &RelayState, tls: Option<&TlsConfig>) -> Result<(), WebmetroError> {
+    let config = args.config()?;
+    let current = state.config();
+    if config.listen != current.listen {
+        warn!("Listen addresses only change when the relay restarts");
+    }
+    if config.log != current.log {
+        warn!("The log filter only changes when the relay restarts");
+    }
+    if config.slate != current.slate {
+        warn!("The slate only changes when the relay restarts");
+    }
+    match (tls, &config.tls) {
+        (Some(tls), Some(paths)) => tls.reload(&paths.cert, &paths.key)?,
+        (None, None) => {},
+        _ => warn!("HTTPS can only be switched on or off when the relay restarts"),
+    }
+    state.update(config);
+    Ok(())
+}
+
+/// Reload the configuration whenever the process receives SIGHUP
+#[cfg(unix)]
+fn reload_on_sighup(args: RelayArgs, state: Arc<RelayState>, tls: Option<TlsConfig>) -> Result<(), WebmetroError> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match reload(&args, &state, tls.as_ref()) {
+                Ok(()) => info!("Reloaded configuration"),
+                Err(err) => warn!("Keeping the previous configuration: {}", err),
+            }
+        }
     });
+    Ok(())
+}
+
+/// The relay's HTTP & WebSocket routes
+fn routes(state: Arc<RelayState>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static {
+    // channels are only opened once the client is let in, so refused requests don't open them
+    let served_state = state.clone();
+    let served = move |name: String| {
+        future::ready(if served_state.config().serves(&name) {
+            Ok(name)
+        } else {
+            debug!("Refused unlisted Channel {}", name);
+            Err(warp::reject::not_found())
+        })
+    };
+    let channel = path!("live" / String).and_then(served.clone());
+
+    let origin = warp::header::optional::<String>("origin");
+    let cors_state = state.clone();
+    let cors = move |response: Response<Body>, origin: Option<String>| {
+        allow_origin(&cors_state.config(), origin.as_deref(), response)
+    };
+
+    // browsers ask before sending credentials cross-origin, to the paths that answer with CORS
+    // headers; this doesn't use warp::options(), which would turn other routes' 404s into 405s
+    let cors_paths = path!("live" / String)
+        .map(|_| ())
+        .untuple_one()
+        .or(path!("api" / "channels" / ..))
+        .unify()
+        .or(path!("dash" / ..))
+        .unify();
+    let preflight_state = state.clone();
+    let preflight = cors_paths
+        .and(warp::method())
+        .and_then(|method: Method| {
+            future::ready(match method {
+                Method::OPTIONS => Ok(()),
+                _ => Err(warp::reject::not_found()),
+            })
+        })
+        .untuple_one()
+        .and(origin)
+        .map(move |origin: Option<String>| {
+            let response = Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .header(ACCESS_CONTROL_ALLOW_METHODS, "GET, HEAD")
+                .header(ACCESS_CONTROL_ALLOW_HEADERS, "Authorization")
+                .header(ACCESS_CONTROL_MAX_AGE, "86400")
+                .body(Body::empty())
+                .unwrap();
+            allow_origin(&preflight_state.config(), origin.as_deref(), response)
+        });
+
+    let head = channel
+        .clone()
+        .and(warp::head())
+        .map(|name| {
+            info!("HEAD Request For Channel {}", name);
+            media_response(Body::empty())
+        })
+        .and(origin)
+        .map(cors.clone());
+
+    let client = warp::header::optional::<String>("authorization").and(warp::addr::remote());
+
+    let get_state = state.clone();
+    let get = channel
+        .clone()
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(client)
+        .map(move |name: String, query: HashMap<String, String>, authorization, remote| {
+            if let Some(response) = rejection(&get_state.config().auth, &name, Role::Viewer, authorization, &query, remote) {
+                return response;
+            }
+            let channel = match get_state.channel(&name) {
+                Some(channel) => channel,
+                None => return no_such_channel(),
+            };
+            let offset = match requested_offset(&query, &name) {
+                Ok(offset) => offset,
+                Err(message) => {
+                    return Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Body::from(message))
+                        .unwrap()
+                }
+            };
+            info!("Listener Connected On Channel {}", name);
+            media_response(Body::wrap_stream(get_stream(channel, offset)))
+        })
+        .and(origin)
+        .map(cors.clone());
+
+    let ws_state = state.clone();
+    let ws = path!("ws" / String)
+        .and_then(served.clone())
+        .and(warp::ws())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(client)
+        .map(move |name: String, ws: Ws, query: HashMap<String, String>, authorization, remote| {
+            if let Some(response) = rejection(&ws_state.config().auth, &name, Role::Viewer, authorization, &query, remote) {
+                return response;
+            }
+            let channel = match ws_state.channel(&name) {
+                Some(channel) => channel,
+                None => return no_such_channel(),
+            };
+            let offset = match requested_offset(&query, &name) {
+                Ok(offset) => offset,
+                Err(message) => {
+                    return Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Body::from(message))
+                        .unwrap()
+                }
+            };
+            info!("WebSocket Listener Connected On Channel {}", name);
+            ws.on_upgrade(move |socket| websocket_stream(socket, channel, offset)).into_response()
+        });
+
+    let watch_state = state.clone();
+    let watch = path!("watch" / String)
+        .and(warp::get())
+        .and_then(move |name: String| {
+            let config = watch_state.config();
+            future::ready(if config.watch_page && config.serves(&name) {
+                Ok(Response::builder()
+                    .header(CONTENT_TYPE, "text/html; charset=utf-8")
+                    .body(Body::from(WATCH_PAGE))
+                    .unwrap())
+            } else {
+                Err(warp::reject::not_found())
+            })
+        });
+
+    let list_state = state.clone();
+    let list = path!("api" / "channels")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(client)
+        .map(move |query: HashMap<String, String>, authorization: Option<String>, _| {
+            // only the channels the client could watch are listed
+            let config = list_state.config();
+            let credential = credential(authorization.as_deref(), &query);
+            let statuses: Vec<ChannelStatus> = list_state
+                .statuses()
+                .into_iter()
+                .filter(|status| match config.auth {
+                    Some(ref access) => access.check(&status.name, Role::Viewer, credential).is_ok(),
+                    None => true,
+                })
+                .collect();
+            warp::reply::json(&statuses).into_response()
+        })
+        .and(origin)
+        .map(cors.clone());
+
+    let status_state = state.clone();
+    let status = path!("api" / "channels" / String)
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(client)
+        .map(move |name: String, query: HashMap<String, String>, authorization, remote| {
+            if let Some(response) = rejection(&status_state.config().auth, &name, Role::Viewer, authorization, &query, remote) {
+                return response;
+            }
+            match status_state.status(&name) {
+                Some(status) => warp::reply::json(&status).into_response(),
+                None => no_such_channel(),
+            }
+        })
+        .and(origin)
+        .map(cors.clone());
+
+    let metrics_state = state.clone();
+    let metrics = path!("metrics")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(client)
+        .map(move |query: HashMap<String, String>, authorization: Option<String>, remote| {
+            // the metrics name every open channel, so with access rules they need a key of their own
+            if let Some(ref access) = metrics_state.config().auth {
+                if let Err(denial) = access.check_metrics(credential(authorization.as_deref(), &query)) {
+                    return refusal(denial, "metrics request", remote);
+                }
+            }
+            Response::builder()
+                .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(Body::from(metrics_state.render_metrics()))
+                .unwrap()
+        });
+
+    let admin_state = state.clone();
+    let admin = path!("admin" / "channels" / String / String)
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(client)
+        .map(move |name: String, action: String, query: HashMap<String, String>, authorization, remote| {
+            // unlike publishing & viewing, administration is never open to everyone
+            let access = Some(admin_state.config().auth.clone().unwrap_or_default());
+            if let Some(response) = rejection(&access, &name, Role::Admin, authorization, &query, remote) {
+                return response;
+            }
+            let (status, message) = admin_state.administer(&name, &action);
+            Response::builder().status(status).body(Body::from(message)).unwrap()
+        });
+
+    let manifest_state = state.clone();
+    let dash_manifest = path!("dash" / String / "manifest.mpd")
+        .and_then(served.clone())
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(client)
+        .map(move |name: String, query: HashMap<String, String>, authorization, remote| {
+            if let Some(response) = rejection(&manifest_state.config().auth, &name, Role::Viewer, authorization, &query, remote) {
+                return response;
+            }
+            let channel = match manifest_state.channel(&name) {
+                Some(channel) => channel,
+                None => return no_such_channel(),
+            };
+            let session = manifest_state.dash_session(&name, channel);
+            // players don't pass the manifest's query on to segments, so the token has to be in their URLs
+            let suffix = match query.get("token") {
+                Some(token) => format!("?token={}", query_escape(token)),
+                None => String::new(),
+            };
+            let prefix = format!("{}/", session.id);
+            let manifest = session.packager.lock().expect("Locking DASH packager").manifest(SystemTime::now(), &prefix, &suffix);
+            Response::builder()
+                .header(CONTENT_TYPE, "application/dash+xml")
+                .header(CACHE_CONTROL, "no-cache")
+                .body(Body::from(manifest))
+                .unwrap()
+        })
+        .and(origin)
+        .map(cors.clone());
 
+    let segment_state = state.clone();
+    let dash_segment = path!("dash" / String / u128 / String)
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(client)
+        .map(move |name: String, id: u128, file: String, query: HashMap<String, String>, authorization, remote| {
+            if let Some(response) = rejection(&segment_state.config().auth, &name, Role::Viewer, authorization, &query, remote) {
+                return response;
+            }
+            let session = segment_state.dash.lock().expect("Locking DASH sessions").get(&name).cloned();
+            let segment = session.filter(|session| session.id == id).and_then(|session| {
+                session.touch();
+                let packager = session.packager.lock().expect("Locking DASH packager");
+                match file.strip_prefix("init-").and_then(|file| file.strip_suffix(".webm")) {
+                    Some(period) => packager.init(period.parse().ok()?),
+                    None => packager.segment(file.strip_suffix(".webm")?.parse().ok()?),
+                }
+            });
+            match segment {
+                // a segment's URL always refers to the same bytes
+                Some(bytes) => Response::builder()
+                    .header(CONTENT_TYPE, "video/webm")
+                    .header(CACHE_CONTROL, "max-age=3600")
+                    .body(Body::from(bytes))
+                    .unwrap(),
+                None => Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from("no such segment"))
+                    .unwrap(),
+            }
+        })
+        .and(origin)
+        .map(cors);
+
+    let ws_publish_state = state.clone();
     let post_put = channel
         .clone()
         .and(warp::post().or(warp::put()).unify())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(client)
         .and(warp::body::stream())
-        .map(|(channel, name), stream| {
-            info!("Source Connected On Channel {}", name);
-            Response::new(Body::wrap_stream(post_stream(channel, stream)))
+        .map(move |name: String, query: HashMap<String, String>, authorization, remote, stream| {
+            let metrics = state.metrics.clone();
+            state.connect_source(&name, &query, authorization, remote, |transmitter, limits, recorder| {
+                Response::new(Body::wrap_stream(post_stream(transmitter, limits, recorder, metrics, stream)))
+            })
+        });
+
+    let ws_publish = path!("ws" / String / "publish")
+        .and_then(served)
+        .and(warp::ws())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(client)
+        .map(move |name: String, ws: Ws, query: HashMap<String, String>, authorization, remote| {
+            let metrics = ws_publish_state.metrics.clone();
+            ws_publish_state.connect_source(&name, &query, authorization, remote, |transmitter, limits, recorder| {
+                ws.on_upgrade(move |socket| websocket_source(socket, transmitter, limits, recorder, metrics)).into_response()
+            })
         });
 
-    let routes = head.or(get).or(post_put);
+    head.or(get).or(post_put).or(list).or(status).or(metrics).or(admin).or(watch).or(ws).or(ws_publish).or(dash_manifest).or(dash_segment).or(preflight)
+}
+
+pub fn run(args: RelayArgs) -> Result<(), WebmetroError> {
+    let config = args.config();
+    // a log filter from the config file applies unless RUST_LOG is set
+    match config.as_ref().ok().and_then(|config| config.log.clone()) {
+        Some(filter) => env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(filter)).init(),
+        None => env_logger::init(),
+    }
+    serve(args, config?)
+}
+
+#[tokio::main]
+async fn serve(args: RelayArgs, config: RelayConfig) -> Result<(), WebmetroError> {
+    let slate = match config.slate {
+        Some(ref path) => Some(load_slate(path).await?),
+        None => None,
+    };
 
-    let mut server_futures: FuturesUnordered<_> = addrs
-        .map(|addr| warp::serve(routes.clone()).try_bind(addr))
-        .collect();
+    let tls = match config.tls {
+        Some(ref paths) => Some(TlsConfig::load(&paths.cert, &paths.key)?),
+        None => None,
+    };
+
+    let mut addrs = Vec::new();
+    for addr_str in config.listen.iter() {
+        addrs.extend(addr_str.to_socket_addrs()?);
+    }
+    info!("Binding to {:?}", addrs);
+    if addrs.is_empty() {
+        return Err("Listen address didn't resolve".into());
+    }
+
+    let state = RelayState::new(config, slate);
+    #[cfg(unix)]
+    reload_on_sighup(args, state.clone(), tls.clone())?;
+    #[cfg(not(unix))]
+    drop(args);
+
+    let routes = routes(state);
+
+    let mut server_futures = FuturesUnordered::<Pin<Box<dyn Future<Output = ()> + Send>>>::new();
+    for addr in addrs {
+        match tls {
+            Some(ref tls) => {
+                let listener = TcpListener::bind(addr).await?;
+                server_futures.push(Box::pin(serve_tls(routes.clone(), listener, tls.clone())));
+            },
+            None => server_futures.push(Box::pin(warp::serve(routes.clone()).try_bind(addr))),
+        }
+    }
 
-    while let Some(_) = server_futures.next().await {}
+    while server_futures.next().await.is_some() {}
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use warp::test::{request, ws, RequestBuilder};
+    use webmetro::chunk::ClusterHead;
+
+    use super::*;
+
+    const AUTH: &str = r#"
+        listen = ["127.0.0.1:0"]
+
+        [auth]
+        metrics = ["scraper"]
+
+        [auth.default]
+        publish = ["publish-key"]
+        admin = ["operator"]
+
+        [auth.channels.private]
+        view = ["viewer-token"]
+    "#;
+
+    fn relay(config: &str) -> Arc<RelayState> {
+        RelayState::new(toml::from_str(config).unwrap(), None)
+    }
+
+    /// Route a request without reading the response body, since live streams don't end
+    async fn respond(state: &Arc<RelayState>, request: RequestBuilder) -> Result<Response<Body>, Rejection> {
+        request.filter(&routes(state.clone())).await.map(Reply::into_response)
+    }
+
+    async fn status(state: &Arc<RelayState>, request: RequestBuilder) -> StatusCode {
+        respond(state, request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn publishing_needs_a_key() {
+        let state = relay(AUTH);
+        let response = respond(&state, request().method("PUT").path("/live/main")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer");
+
+        let wrong = request().method("PUT").path("/live/main").header("authorization", "Bearer wrong");
+        assert_eq!(status(&state, wrong).await, StatusCode::FORBIDDEN);
+        let header = request().method("PUT").path("/live/main").header("authorization", "Bearer publish-key");
+        assert_eq!(status(&state, header).await, StatusCode::OK);
+        let query = request().method("POST").path("/live/main?token=publish-key");
+        assert_eq!(status(&state, query).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn viewing_needs_a_token_where_configured() {
+        let state = relay(AUTH);
+        let response = respond(&state, request().path("/live/main")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "video/webm");
+
+        assert_eq!(status(&state, request().path("/live/private")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(&state, request().path("/live/private?token=wrong")).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(&state, request().path("/live/private?token=viewer-token")).await, StatusCode::OK);
+        let header = request().path("/live/private").header("authorization", "Bearer viewer-token");
+        assert_eq!(status(&state, header).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn statuses_need_a_view_token() {
+        let state = relay(AUTH);
+        let _main = state.channel("main").unwrap();
+        let _private = state.channel("private").unwrap();
+        let routes = routes(state.clone());
+        let listed = request().path("/api/channels").reply(&routes).await;
+        let listed = String::from_utf8_lossy(listed.body());
+        assert!(listed.contains(r#""name":"main""#) && !listed.contains(r#""name":"private""#));
+        let listed = request().path("/api/channels?token=viewer-token").reply(&routes).await;
+        let listed = String::from_utf8_lossy(listed.body());
+        assert!(listed.contains(r#""name":"main""#) && listed.contains(r#""name":"private""#));
+
+        assert_eq!(status(&state, request().path("/api/channels/main")).await, StatusCode::OK);
+        assert_eq!(status(&state, request().path("/api/channels/private")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(&state, request().path("/api/channels/private?token=wrong")).await, StatusCode::FORBIDDEN);
+        let header = request().path("/api/channels/private").header("authorization", "Bearer viewer-token");
+        assert_eq!(status(&state, header).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn metrics_need_a_key() {
+        let state = relay(AUTH);
+        assert_eq!(status(&state, request().path("/metrics")).await, StatusCode::UNAUTHORIZED);
+        let operator = request().path("/metrics").header("authorization", "Bearer operator");
+        assert_eq!(status(&state, operator).await, StatusCode::FORBIDDEN);
+        let response = respond(&state, request().path("/metrics").header("authorization", "Bearer scraper")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/plain; version=0.0.4");
+
+        let open = relay("listen = [\"127.0.0.1:0\"]");
+        assert_eq!(status(&open, request().path("/metrics")).await, StatusCode::OK);
+    }
+
+    fn admin(channel: &str, action: &str) -> RequestBuilder {
+        request()
+            .method("POST")
+            .path(&format!("/admin/channels/{}/{}", channel, action))
+            .header("authorization", "Bearer operator")
+    }
+
+    fn publish(channel: &str) -> RequestBuilder {
+        request().method("PUT").path(&format!("/live/{}?token=publish-key", channel))
+    }
+
+    #[tokio::test]
+    async fn admin_routes_need_an_admin_key() {
+        let state = relay(AUTH);
+        let anonymous = request().method("POST").path("/admin/channels/main/lock");
+        assert_eq!(status(&state, anonymous).await, StatusCode::UNAUTHORIZED);
+        let publisher = request().method("POST").path("/admin/channels/main/lock?token=publish-key");
+        assert_eq!(status(&state, publisher).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(&state, admin("main", "reboot")).await, StatusCode::NOT_FOUND);
+
+        // without access rules, the admin routes are closed rather than open
+        let open = relay("listen = [\"127.0.0.1:0\"]");
+        assert_eq!(status(&open, admin("main", "lock")).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn admin_locks_channels() {
+        let state = relay(AUTH);
+        let source = respond(&state, publish("main")).await.unwrap();
+        assert_eq!(status(&state, admin("main", "lock")).await, StatusCode::OK);
+        assert_eq!(status(&state, publish("main")).await, StatusCode::LOCKED);
+        assert_eq!(status(&state, publish("other")).await, StatusCode::OK);
+
+        // the current source stays connected
+        assert!(state.status("main").unwrap().transmitter_connected);
+        drop(source);
+
+        assert_eq!(status(&state, admin("main", "unlock")).await, StatusCode::OK);
+        assert_eq!(status(&state, publish("main")).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn admin_kicks_sources_and_listeners() {
+        let state = relay(AUTH);
+        let routes = routes(state.clone());
+        let _source = respond(&state, publish("main")).await.unwrap();
+        let _first = respond(&state, request().path("/live/main")).await.unwrap();
+        let _second = respond(&state, request().path("/live/main")).await.unwrap();
+
+        let kicked = admin("main", "kick-source").reply(&routes).await;
+        assert_eq!((kicked.status(), kicked.body().as_ref()), (StatusCode::OK, &b"source disconnected"[..]));
+        assert!(!state.status("main").unwrap().transmitter_connected);
+        let kicked = admin("main", "kick-source").reply(&routes).await;
+        assert_eq!(kicked.status(), StatusCode::NOT_FOUND);
+
+        let kicked = admin("main", "kick-listeners").reply(&routes).await;
+        assert_eq!((kicked.status(), kicked.body().as_ref()), (StatusCode::OK, &b"2 listeners disconnected"[..]));
+    }
+
+    const CORS: &str = r#"
+        listen = ["127.0.0.1:0"]
+        cors-origins = ["https://example.com"]
+    "#;
+
+    #[tokio::test]
+    async fn cors_headers_for_allowed_origins() {
+        let state = relay(CORS);
+        for path in &["/live/main", "/api/channels", "/dash/main/manifest.mpd"] {
+            let response = respond(&state, request().path(path).header("origin", "https://example.com")).await.unwrap();
+            assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com", "{}", path);
+            assert_eq!(response.headers()[VARY], "Origin", "{}", path);
+        }
+        let head = request().method("HEAD").path("/live/main").header("origin", "https://example.com");
+        let response = respond(&state, head).await.unwrap();
+        assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com");
+
+        let other = respond(&state, request().path("/live/main").header("origin", "https://example.org")).await.unwrap();
+        assert!(!other.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn preflight_only_for_cors_paths() {
+        let state = relay(CORS);
+        let preflight = |path: &str| request().method("OPTIONS").path(path).header("origin", "https://example.com");
+        for path in &["/live/main", "/api/channels", "/api/channels/main", "/dash/main/manifest.mpd"] {
+            let response = respond(&state, preflight(path)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT, "{}", path);
+            assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com", "{}", path);
+            assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_HEADERS], "Authorization", "{}", path);
+        }
+        for path in &["/admin/channels/main/lock", "/metrics", "/live/main/extra"] {
+            assert!(respond(&state, preflight(path)).await.is_err(), "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket_sends_a_message_per_chunk() {
+        let state = relay(AUTH);
+        let transmitter = Transmitter::new(state.channel("main").unwrap()).unwrap();
+        let mut viewer = ws().path("/ws/main").handshake(routes(state.clone())).await.unwrap();
+
+        transmitter.send(Chunk::Headers { bytes: Bytes::from_static(b"header") }).unwrap();
+        let mut head = ClusterHead::new(0);
+        head.keyframe = true;
+        transmitter.send(Chunk::Cluster(head, Bytes::from_static(b"blocks"))).unwrap();
+
+        assert_eq!(viewer.recv().await.unwrap().as_bytes(), b"header");
+        let cluster = viewer.recv().await.unwrap();
+        assert!(cluster.is_binary());
+        assert_eq!(cluster.as_bytes()[..4], [0x1F, 0x43, 0xB6, 0x75]);
+        assert!(cluster.as_bytes().ends_with(b"blocks"));
+
+        // the end of the channel ends the stream
+        assert_eq!(state.channel("main").unwrap().lock().unwrap().kick_listeners(), 1);
+        viewer.recv_closed().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn websocket_viewers_need_a_token_where_configured() {
+        let state = relay(AUTH);
+        assert!(ws().path("/ws/private").handshake(routes(state.clone())).await.is_err());
+        assert!(ws().path("/ws/private?token=wrong").handshake(routes(state.clone())).await.is_err());
+        assert!(ws().path("/ws/private?token=viewer-token").handshake(routes(state)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn refused_requests_open_no_channels() {
+        let state = relay(AUTH);
+        assert_eq!(status(&state, request().path("/live/private")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(&state, request().path("/dash/private/manifest.mpd")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(&state, request().method("PUT").path("/live/main")).await, StatusCode::UNAUTHORIZED);
+        assert!(ws().path("/ws/private").handshake(routes(state.clone())).await.is_err());
+        assert!(ws().path("/ws/main/publish").handshake(routes(state.clone())).await.is_err());
+        // the map keeps a dropped channel's entry until it's next cleaned up, so this would
+        // also notice a channel that was opened & closed again
+        assert!(state.channels.lock().unwrap().is_empty());
+    }
+
+    const TEST_FILE: &[u8] = include_bytes!("../data/test1.webm");
+
+    #[tokio::test]
+    async fn websocket_sources_joined_into_one_stream() {
+        let state = relay(AUTH);
+        let routes = routes(state.clone());
+        assert!(ws().path("/ws/main/publish").handshake(routes.clone()).await.is_err());
+        assert!(ws().path("/ws/main/publish?token=wrong").handshake(routes.clone()).await.is_err());
+
+        let mut source = ws().path("/ws/main/publish?token=publish-key").handshake(routes.clone()).await.unwrap();
+        let mut listener = Listener::new(state.channel("main").unwrap());
+        // messages needn't line up with EBML elements
+        source.send(Message::binary(&TEST_FILE[..1000])).await;
+        source.send(Message::text("ignored")).await;
+        source.send(Message::binary(&TEST_FILE[1000..])).await;
+        assert!(matches!(listener.next().await, Some(Chunk::Headers { .. })));
+        assert!(matches!(listener.next().await, Some(Chunk::Cluster(head, _)) if head.keyframe));
+
+        // a kicked source is told why
+        assert_eq!(admin("main", "kick-source").reply(&routes).await.status(), StatusCode::OK);
+        let reason = source.recv().await.unwrap();
+        assert_eq!(reason.to_str(), Ok("Channel error: an administrator disconnected the source"));
+        source.recv_closed().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn dash_manifest_served_before_any_segments() {
+        let state = relay(AUTH);
+        let response = respond(&state, request().path("/dash/main/manifest.mpd")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/dash+xml");
+        assert_eq!(status(&state, request().path("/dash/private/manifest.mpd")).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn anyone_may_publish_without_access_rules() {
+        let state = relay("listen = [\"127.0.0.1:0\"]");
+        assert_eq!(status(&state, request().method("PUT").path("/live/main")).await, StatusCode::OK);
+        assert_eq!(status(&state, request().path("/live/main")).await, StatusCode::OK);
+    }
+}
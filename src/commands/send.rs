@@ -37,10 +37,10 @@ pub struct SendArgs {
 
 #[tokio::main]
 pub async fn run(args: SendArgs) -> Result<(), WebmetroError> {
-    let start_time = args.skip.map_or(0, |s| s.as_millis());
+    let start_time = args.skip.map_or(0, |s| s.as_nanos());
     let stop_time = args
         .take
-        .map_or(std::u128::MAX, |t| t.as_millis() + start_time);
+        .map_or(u128::MAX, |t| t.as_nanos() + start_time);
 
     // build pipeline
     let mut timecode_fixer = ChunkTimecodeFixer::new();
@@ -0,0 +1,255 @@
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::server::conn::Http;
+use tokio::net::TcpListener;
+use tokio::time::{sleep, timeout};
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    TlsAcceptor,
+};
+use warp::{Filter, Reply};
+use webmetro::error::WebmetroError;
+
+/// How long a client gets to complete the TLS handshake before it's disconnected,
+/// so stalled connections don't pile up
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A server certificate & key loaded from PEM files, which can be reloaded
+/// without disturbing connections that are already open
+#[derive(Clone)]
+pub struct TlsConfig {
+    current: Arc<Mutex<Arc<ServerConfig>>>,
+}
+
+fn tls_error(path: &Path, message: impl std::fmt::Display) -> WebmetroError {
+    WebmetroError::ApplicationError {
+        message: format!("{}: {}", path.display(), message),
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, WebmetroError> {
+    let mut reader = BufReader::new(File::open(path).map_err(|err| tls_error(path, err))?);
+    let certs = rustls_pemfile::certs(&mut reader).map_err(|err| tls_error(path, err))?;
+    if certs.is_empty() {
+        return Err(tls_error(path, "no certificates found"));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, WebmetroError> {
+    let mut reader = BufReader::new(File::open(path).map_err(|err| tls_error(path, err))?);
+    for item in rustls_pemfile::read_all(&mut reader).map_err(|err| tls_error(path, err))? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(tls_error(path, "no private key found"))
+}
+
+fn load_server_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>, WebmetroError> {
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(read_certs(cert_path)?, read_key(key_path)?)
+        .map_err(|err| tls_error(key_path, err))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+impl TlsConfig {
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<TlsConfig, WebmetroError> {
+        Ok(TlsConfig {
+            current: Arc::new(Mutex::new(load_server_config(cert_path, key_path)?)),
+        })
+    }
+
+    /// Switch to the certificate & key in the given files (which may be the
+    /// original ones, rewritten); new connections use them if they're valid
+    pub fn reload(&self, cert_path: &Path, key_path: &Path) -> Result<(), WebmetroError> {
+        let config = load_server_config(cert_path, key_path)?;
+        *self.current.lock().expect("Locking TLS config") = config;
+        Ok(())
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.lock().expect("Locking TLS config").clone())
+    }
+}
+
+/// Serve the filter over HTTPS on the given listener, with the config's current certificate
+pub async fn serve_tls<F>(filter: F, listener: TcpListener, config: TlsConfig)
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    serve_tls_with_timeout(filter, listener, config, HANDSHAKE_TIMEOUT).await
+}
+
+async fn serve_tls_with_timeout<F>(filter: F, listener: TcpListener, config: TlsConfig, handshake_timeout: Duration)
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(connection) => connection,
+            // like hyper's own listener, per-connection errors are skipped, but
+            // anything else (e.g. running out of file descriptors) backs off first
+            Err(err) if is_connection_error(&err) => continue,
+            Err(err) => {
+                warn!("Failed to accept connection: {}", err);
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let acceptor = config.acceptor();
+        let service = warp::service(filter.clone());
+        tokio::spawn(async move {
+            let stream = match timeout(handshake_timeout, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    debug!("TLS handshake with {} failed: {}", remote, err);
+                    return;
+                }
+                Err(_) => {
+                    debug!("TLS handshake with {} timed out", remote);
+                    return;
+                }
+            };
+            if let Err(err) = Http::new().serve_connection(stream, service).with_upgrades().await {
+                debug!("Connection with {} failed: {}", remote, err);
+            }
+        });
+    }
+}
+
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionRefused | ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::path::PathBuf;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::{
+        rustls::{self, Certificate, ClientConfig, RootCertStore},
+        TlsConnector,
+    };
+    use warp::Filter;
+
+    use super::*;
+
+    /// Writes a fresh self-signed certificate for localhost & its key to temporary files
+    fn self_signed(name: &str) -> (PathBuf, PathBuf, Certificate) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("webmetro-{}-{}.crt", name, std::process::id()));
+        let key_path = dir.join(format!("webmetro-{}-{}.key", name, std::process::id()));
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        (cert_path, key_path, Certificate(cert.serialize_der().unwrap()))
+    }
+
+    /// Make an HTTPS request trusting only the given certificate, returning the response
+    async fn get(port: u16, trusted: &Certificate) -> Result<String, std::io::Error> {
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client_config));
+
+        let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        let domain = rustls::ServerName::try_from("localhost").unwrap();
+        let mut stream = connector.connect(domain, stream).await?;
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn serves_and_reloads_https() {
+        let (cert_path, key_path, first_cert) = self_signed("serve");
+        let config = TlsConfig::load(&cert_path, &key_path).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve_tls(warp::any().map(|| "hello"), listener, config.clone()));
+
+        let response = get(port, &first_cert).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("hello"));
+
+        // swap in a new certificate; new connections get it
+        let (new_cert_path, new_key_path, second_cert) = self_signed("reload");
+        std::fs::rename(&new_cert_path, &cert_path).unwrap();
+        std::fs::rename(&new_key_path, &key_path).unwrap();
+        config.reload(&cert_path, &key_path).unwrap();
+        assert!(get(port, &second_cert).await.unwrap().ends_with("hello"));
+        assert!(get(port, &first_cert).await.is_err());
+
+        // a broken certificate is refused, and the previous one stays in use
+        std::fs::write(&cert_path, "not a certificate").unwrap();
+        assert!(config.reload(&cert_path, &key_path).is_err());
+        assert!(get(port, &second_cert).await.unwrap().ends_with("hello"));
+
+        // the certificate can also move to different files
+        let (moved_cert_path, moved_key_path, third_cert) = self_signed("moved");
+        config.reload(&moved_cert_path, &moved_key_path).unwrap();
+        assert!(get(port, &third_cert).await.unwrap().ends_with("hello"));
+
+        for path in [cert_path, key_path, moved_cert_path, moved_key_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_handshake_dropped() {
+        let (cert_path, key_path, cert) = self_signed("stalled");
+        let config = TlsConfig::load(&cert_path, &key_path).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let filter = warp::any().map(|| "hello");
+        tokio::spawn(serve_tls_with_timeout(filter, listener, config, Duration::from_millis(100)));
+
+        // a client that never starts the handshake is disconnected
+        let mut stalled = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut buf = [0; 16];
+        let read = timeout(Duration::from_secs(5), stalled.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+
+        // without holding up anyone else
+        assert!(get(port, &cert).await.unwrap().ends_with("hello"));
+
+        std::fs::remove_file(cert_path).unwrap();
+        std::fs::remove_file(key_path).unwrap();
+    }
+
+    #[test]
+    fn missing_files_rejected() {
+        let (cert_path, key_path, _) = self_signed("missing");
+        assert!(TlsConfig::load(&cert_path, Path::new("/nonexistent/key.pem")).is_err());
+        // the certificate file doesn't hold a key
+        assert!(TlsConfig::load(&cert_path, &cert_path).is_err());
+        assert!(TlsConfig::load(&cert_path, &key_path).is_ok());
+
+        std::fs::remove_file(cert_path).unwrap();
+        std::fs::remove_file(key_path).unwrap();
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+
+use crate::chunk::Chunk;
+use crate::datetime::utc_datetime;
+use crate::fixers::SourceSplicer;
+use crate::tracks::{tracks_compatible, TrackEntry, TrackType};
+
+/// How many finished segments a `DashPackager` keeps by default
+pub const DEFAULT_DASH_SEGMENTS: usize = 10;
+
+/// A run of segments sharing an initialization segment; a new one starts
+/// whenever a source with different tracks or TimecodeScale takes over
+struct Period {
+    id: u64,
+    init: Bytes,
+    tracks: Vec<TrackEntry>,
+    timecode_scale: Option<u64>,
+    /// media time of its first segment, in milliseconds
+    start: Option<u64>,
+}
+
+/// Clusters from one keyframe up to the next, as a DASH media segment
+struct Segment {
+    number: u64,
+    period: u64,
+    /// in milliseconds of media time
+    start: u64,
+    duration: u64,
+    bytes: Bytes,
+}
+
+/// The segment still being filled, until the next keyframe ends it
+struct Pending {
+    period: u64,
+    start: u64,
+    bytes: BytesMut,
+}
+
+/// Splits a channel's chunks into DASH segments, one per keyframe, keeping the
+/// latest few & a live manifest describing them
+pub struct DashPackager {
+    max_segments: usize,
+    splicer: SourceSplicer,
+    periods: VecDeque<Period>,
+    segments: VecDeque<Segment>,
+    pending: Option<Pending>,
+    next_period: u64,
+    next_number: u64,
+    /// the wall clock time at media time zero, fixed when the first segment starts
+    availability_start: Option<SystemTime>,
+}
+
+/// The DASH codecs parameter for a Matroska codec ID, for the codecs browsers can play from WebM
+fn dash_codec(codec_id: &str) -> Option<&'static str> {
+    match codec_id {
+        "V_VP8" => Some("vp8"),
+        "V_VP9" => Some("vp9"),
+        "A_OPUS" => Some("opus"),
+        "A_VORBIS" => Some("vorbis"),
+        _ => None,
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// An xs:duration of the given number of milliseconds
+fn xs_duration(millis: u64) -> String {
+    format!("PT{}.{:03}S", millis / 1000, millis % 1000)
+}
+
+impl DashPackager {
+    pub fn new() -> DashPackager {
+        DashPackager {
+            max_segments: DEFAULT_DASH_SEGMENTS,
+            splicer: SourceSplicer::new(),
+            periods: VecDeque::new(),
+            segments: VecDeque::new(),
+            pending: None,
+            next_period: 0,
+            next_number: 0,
+            availability_start: None,
+        }
+    }
+
+    /// Keep this many finished segments (at least one)
+    pub fn with_max_segments(mut self, max_segments: usize) -> Self {
+        self.max_segments = max_segments.max(1);
+        self
+    }
+
+    /// Take in the channel's next chunk, as received by a `Listener`
+    pub fn push(&mut self, chunk: Chunk) {
+        // a new source's clusters can't be added to the last one's segment, but
+        // that segment only ends where the new source starts, so there's no gap
+        let chunk = match self.splicer.process(chunk) {
+            Some(chunk) => chunk,
+            None => return,
+        };
+        match chunk {
+            Chunk::Headers { ref bytes } => {
+                self.push_header(bytes.clone(), chunk.tracks().unwrap_or_default(), chunk.timecode_scale())
+            },
+            Chunk::Cluster(ref head, _) => {
+                let start = (head.start_nanos() / 1_000_000) as u64;
+                let keyframe = head.keyframe;
+                self.push_cluster(start, keyframe, chunk);
+            }
+            _ => {}
+        }
+    }
+
+    fn push_header(&mut self, init: Bytes, tracks: Vec<TrackEntry>, timecode_scale: Option<u64>) {
+        // the old init segment would have players misread the new timecodes otherwise
+        if let Some(period) = self.periods.back() {
+            if tracks_compatible(&period.tracks, &tracks) && period.timecode_scale == timecode_scale {
+                return;
+            }
+        }
+        self.periods.push_back(Period {
+            id: self.next_period,
+            init,
+            tracks,
+            timecode_scale,
+            start: None,
+        });
+        self.next_period += 1;
+        self.trim();
+    }
+
+    /// Add a cluster (starting at the given millisecond) to the pending segment
+    fn push_cluster(&mut self, start: u64, keyframe: bool, cluster: Chunk) {
+        if keyframe {
+            self.finish_segment(start);
+        }
+        if self.periods.is_empty() {
+            return;
+        }
+        self.availability_start.get_or_insert_with(|| {
+            SystemTime::now().checked_sub(Duration::from_millis(start)).unwrap_or(UNIX_EPOCH)
+        });
+        let period = self.periods.back_mut().unwrap();
+        let pending = self.pending.get_or_insert_with(|| {
+            period.start.get_or_insert(start);
+            Pending {
+                period: period.id,
+                start,
+                bytes: BytesMut::new(),
+            }
+        });
+        for bytes in cluster {
+            pending.bytes.extend_from_slice(&bytes);
+        }
+    }
+
+    /// Publish the pending segment, now that the next one starts at `end`
+    fn finish_segment(&mut self, end: u64) {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        let end = end.max(pending.start + 1);
+        self.segments.push_back(Segment {
+            number: self.next_number,
+            period: pending.period,
+            start: pending.start,
+            duration: end - pending.start,
+            bytes: pending.bytes.freeze(),
+        });
+        self.next_number += 1;
+        self.trim();
+    }
+
+    /// Forget old segments, and the periods that no longer have any
+    fn trim(&mut self) {
+        while self.segments.len() > self.max_segments {
+            self.segments.pop_front();
+        }
+        while self.periods.len() > 1 {
+            let id = self.periods[0].id;
+            let in_use = self.segments.iter().any(|segment| segment.period == id)
+                || matches!(self.pending, Some(ref pending) if pending.period == id);
+            if in_use {
+                break;
+            }
+            self.periods.pop_front();
+        }
+    }
+
+    /// The initialization segment of a period, if it's still listed
+    pub fn init(&self, period: u64) -> Option<Bytes> {
+        self.periods.iter().find(|p| p.id == period).map(|p| p.init.clone())
+    }
+
+    /// A finished media segment, if it's still kept
+    pub fn segment(&self, number: u64) -> Option<Bytes> {
+        self.segments.iter().find(|s| s.number == number).map(|s| s.bytes.clone())
+    }
+
+    /// A live manifest listing the kept segments; until there are any, it lists none
+    /// and has players check back every second.
+    /// Segment URLs are `{prefix}init-{period}.webm{suffix}` and `{prefix}{number}.webm{suffix}`.
+    pub fn manifest(&self, now: SystemTime, prefix: &str, suffix: &str) -> String {
+        let availability_start = self.availability_start.unwrap_or(now);
+        let (prefix, suffix) = (escape_xml(prefix), escape_xml(suffix));
+        let longest = self.segments.iter().map(|s| s.duration).max().unwrap_or(1000);
+        let depth: u64 = self.segments.iter().map(|s| s.duration).sum();
+
+        let mut mpd = String::new();
+        writeln!(mpd, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+        writeln!(
+            mpd,
+            r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-live:2011" type="dynamic" availabilityStartTime="{}" publishTime="{}" minimumUpdatePeriod="{}" minBufferTime="{}" timeShiftBufferDepth="{}" suggestedPresentationDelay="{}">"#,
+            utc_datetime(availability_start),
+            utc_datetime(now),
+            xs_duration(longest),
+            xs_duration(longest),
+            xs_duration(depth),
+            xs_duration(longest * 2),
+        ).unwrap();
+
+        for period in self.periods.iter() {
+            let segments: Vec<&Segment> = self.segments.iter().filter(|s| s.period == period.id).collect();
+            let (first, start) = match (segments.first(), period.start) {
+                (Some(first), Some(start)) => (first, start),
+                _ => continue,
+            };
+            let video = period.tracks.iter().any(|track| track.track_type == TrackType::Video);
+            let codecs: Vec<&str> = period.tracks.iter().filter_map(|track| dash_codec(&track.codec_id)).collect();
+            let bytes: usize = segments.iter().map(|s| s.bytes.len()).sum();
+            let millis: u64 = segments.iter().map(|s| s.duration).sum();
+            let bandwidth = (bytes as u64 * 8 * 1000 / millis.max(1)).max(1);
+
+            writeln!(mpd, r#"  <Period id="{}" start="{}">"#, period.id, xs_duration(start)).unwrap();
+            write!(
+                mpd,
+                r#"    <AdaptationSet mimeType="{}""#,
+                if video { "video/webm" } else { "audio/webm" }
+            ).unwrap();
+            if !codecs.is_empty() {
+                write!(mpd, r#" codecs="{}""#, codecs.join(",")).unwrap();
+            }
+            writeln!(mpd, r#" segmentAlignment="true" startWithSAP="1">"#).unwrap();
+            writeln!(mpd, r#"      <Representation id="{}" bandwidth="{}">"#, period.id, bandwidth).unwrap();
+            writeln!(
+                mpd,
+                r#"        <SegmentTemplate timescale="1000" presentationTimeOffset="{}" startNumber="{}" initialization="{}init-{}.webm{}" media="{}$Number$.webm{}">"#,
+                start, first.number, prefix, period.id, suffix, prefix, suffix
+            ).unwrap();
+            writeln!(mpd, "          <SegmentTimeline>").unwrap();
+            for segment in segments {
+                writeln!(mpd, r#"            <S t="{}" d="{}"/>"#, segment.start, segment.duration).unwrap();
+            }
+            writeln!(mpd, "          </SegmentTimeline>").unwrap();
+            writeln!(mpd, "        </SegmentTemplate>").unwrap();
+            writeln!(mpd, "      </Representation>").unwrap();
+            writeln!(mpd, "    </AdaptationSet>").unwrap();
+            writeln!(mpd, "  </Period>").unwrap();
+        }
+
+        writeln!(mpd, r#"  <UTCTiming schemeIdUri="urn:mpeg:dash:utc:direct:2014" value="{}"/>"#, utc_datetime(now)).unwrap();
+        writeln!(mpd, "</MPD>").unwrap();
+        mpd
+    }
+}
+
+impl Default for DashPackager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dash::*;
+    use crate::tests::{cluster, scaled_header, tracks_header};
+
+    fn manifest(packager: &DashPackager) -> String {
+        packager.manifest(SystemTime::now(), "7/", "?token=a&b")
+    }
+
+    #[test]
+    fn segments_split_at_keyframes() {
+        let mut packager = DashPackager::new().with_max_segments(2);
+        packager.push(tracks_header("V_VP9"));
+        // nothing to start from before the first keyframe
+        packager.push(cluster(0, false));
+        packager.push(cluster(1, true));
+        packager.push(cluster(2, false));
+        assert!(!manifest(&packager).contains("<Period "));
+
+        packager.push(cluster(3, true));
+        packager.push(cluster(4, true));
+        packager.push(cluster(5, true));
+        assert_eq!(packager.segment(0), None);
+        assert_eq!(packager.segment(1).unwrap().len(), cluster(3, true).byte_size());
+        assert!(packager.segment(2).is_some());
+        assert!(packager.init(0).is_some());
+
+        let mpd = manifest(&packager);
+        for expected in [
+            r#"<Period id="0" start="PT1.000S">"#,
+            r#"<AdaptationSet mimeType="video/webm" codecs="vp9" segmentAlignment="true" startWithSAP="1">"#,
+            r#"presentationTimeOffset="1000" startNumber="1" initialization="7/init-0.webm?token=a&amp;b" media="7/$Number$.webm?token=a&amp;b""#,
+            r#"<S t="3000" d="1000"/>"#,
+            r#"<S t="4000" d="1000"/>"#,
+            r#"timeShiftBufferDepth="PT2.000S""#,
+        ] {
+            assert!(mpd.contains(expected), "missing {:?} in:\n{}", expected, mpd);
+        }
+        assert!(!mpd.contains(r#"<S t="1000""#));
+    }
+
+    #[test]
+    fn new_codecs_start_a_period() {
+        let mut packager = DashPackager::new();
+        packager.push(tracks_header("V_VP9"));
+        packager.push(cluster(0, true));
+        // a compatible source carries on in the same period, from its first keyframe
+        packager.push(tracks_header("V_VP9"));
+        packager.push(cluster(1, false));
+        packager.push(cluster(2, true));
+        packager.push(tracks_header("V_VP8"));
+        packager.push(cluster(3, true));
+        packager.push(cluster(4, true));
+
+        let mpd = manifest(&packager);
+        assert_eq!(mpd.matches("<Period ").count(), 2);
+        // segments run until the next source's first keyframe
+        assert!(mpd.contains(r#"<S t="0" d="2000"/>"#));
+        assert!(mpd.contains(r#"<S t="2000" d="1000"/>"#));
+        assert!(mpd.contains(r#"<Period id="1" start="PT3.000S">"#));
+        assert!(mpd.contains(r#"codecs="vp8""#));
+        assert!(mpd.contains(r#"startNumber="2" initialization="7/init-1.webm"#));
+        assert!(packager.init(1).is_some());
+        assert!(packager.init(2).is_none());
+    }
+
+    #[test]
+    fn new_timecode_scale_starts_a_period() {
+        let mut packager = DashPackager::new();
+        packager.push(tracks_header("V_VP9"));
+        packager.push(cluster(0, true));
+        packager.push(scaled_header("V_VP9", 100_000));
+        packager.push(cluster(1, true));
+        packager.push(cluster(2, true));
+
+        let mpd = manifest(&packager);
+        assert_eq!(mpd.matches("<Period ").count(), 2);
+        assert!(mpd.contains(r#"<Period id="1" start="PT1.000S">"#));
+        // the new period's init segment has the new Info
+        assert_eq!(packager.init(1).unwrap()[..4], [0x15, 0x49, 0xA9, 0x66]);
+    }
+
+    #[test]
+    fn empty_manifest_before_first_segment() {
+        let now = UNIX_EPOCH + Duration::from_secs(60);
+        let mpd = DashPackager::new().manifest(now, "", "");
+        assert!(mpd.contains(r#"type="dynamic" availabilityStartTime="1970-01-01T00:01:00.000Z""#));
+        assert!(mpd.contains(r#"minimumUpdatePeriod="PT1.000S""#));
+        assert!(!mpd.contains("<Period "));
+        assert!(mpd.ends_with("</MPD>\n"));
+    }
+}
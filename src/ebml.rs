@@ -14,8 +14,21 @@ custom_error!{pub EbmlError
     CorruptPayload       = r#"EBML element payload could not be parsed"#,
 }
 
+impl EbmlError {
+    /// The variant's name, e.g. for labelling metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            EbmlError::CorruptVarint => "CorruptVarint",
+            EbmlError::UnknownElementId => "UnknownElementId",
+            EbmlError::UnknownElementLength => "UnknownElementLength",
+            EbmlError::CorruptPayload => "CorruptPayload",
+        }
+    }
+}
+
 custom_error!{pub WriteError
-    OutOfRange = "EBML Varint out of range"
+    OutOfRange = "EBML Varint out of range",
+    LacingMismatch = "Frames can't be packed with the requested lacing"
 }
 
 #[derive(Debug, PartialEq)]
@@ -37,7 +50,7 @@ pub fn decode_varint(bytes: &[u8]) -> Result<Option<(Varint, usize)>, EbmlError>
     let mut mask: u8 = 0x80;
     let mut unknown_marker: u64 = !0;
 
-    if bytes.len() == 0 {
+    if bytes.is_empty() {
         return Ok(None)
     }
 
@@ -49,7 +62,7 @@ pub fn decode_varint(bytes: &[u8]) -> Result<Option<(Varint, usize)>, EbmlError>
             break
         }
         value_length += 1;
-        mask = mask >> 1;
+        mask >>= 1;
     }
 
     if mask == 0 {
@@ -62,8 +75,8 @@ pub fn decode_varint(bytes: &[u8]) -> Result<Option<(Varint, usize)>, EbmlError>
     }
 
     // decode remaining bytes
-    for i in 1..value_length {
-        value = (value << 8) + (bytes[i] as u64);
+    for byte in &bytes[1..value_length] {
+        value = (value << 8) + (*byte as u64);
         unknown_marker = (unknown_marker << 8) + 0xFF;
     }
 
@@ -103,13 +116,36 @@ pub fn decode_tag(bytes: &[u8]) -> Result<Option<(u64, Varint, usize)>, EbmlErro
 }
 
 pub fn decode_uint(bytes: &[u8]) -> Result<u64, EbmlError> {
-    if bytes.len() < 1 || bytes.len() > 8 {
+    if bytes.is_empty() || bytes.len() > 8 {
         return Err(EbmlError::CorruptPayload);
     }
 
     Ok(BigEndian::read_uint(bytes, bytes.len()))
 }
 
+pub fn decode_int(bytes: &[u8]) -> Result<i64, EbmlError> {
+    if bytes.is_empty() || bytes.len() > 8 {
+        return Err(EbmlError::CorruptPayload);
+    }
+
+    Ok(BigEndian::read_int(bytes, bytes.len()))
+}
+
+pub fn decode_float(bytes: &[u8]) -> Result<f64, EbmlError> {
+    match bytes.len() {
+        0 => Ok(0.0),
+        4 => Ok(BigEndian::read_f32(bytes) as f64),
+        8 => Ok(BigEndian::read_f64(bytes)),
+        _ => Err(EbmlError::CorruptPayload)
+    }
+}
+
+/// Decodes a string element, dropping any null padding at the end
+pub fn decode_string(bytes: &[u8]) -> Result<&str, EbmlError> {
+    let end = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+    std::str::from_utf8(&bytes[..end]).map_err(|_| EbmlError::CorruptPayload)
+}
+
 const SMALL_FLAG: u64 = 0x80;
 const EIGHT_FLAG: u64 = 0x01 << (8*7);
 const EIGHT_MAX: u64 = EIGHT_FLAG - 2;
@@ -129,7 +165,7 @@ pub fn encode_varint<T: Write>(varint: Varint, output: &mut T) -> IoResult<()> {
             while value >= (flag - 1) {
                 // right shift length bit by 1 to indicate adding a new byte;
                 // left shift by 8 because there's a new byte at the end
-                flag = flag << (8 - 1);
+                flag <<= 8 - 1;
                 size += 1;
             };
             (size, flag | value)
@@ -139,7 +175,7 @@ pub fn encode_varint<T: Write>(varint: Varint, output: &mut T) -> IoResult<()> {
     let mut buffer = [0; 8];
     buffer.as_mut().put_uint(number, size);
 
-    return output.write_all(&buffer[..size]);
+    output.write_all(&buffer[..size])
 }
 
 const FOUR_FLAG: u64 = 0x10 << (8*3);
@@ -165,9 +201,9 @@ pub fn encode_element<T: Write + Seek, F: Fn(&mut T) -> IoResult<X>, X>(tag: u64
     encode_varint(Varint::Value(tag), output)?;
     encode_varint_4(Varint::Unknown, output)?;
 
-    let start = output.seek(SeekFrom::Current(0))?;
+    let start = output.stream_position()?;
     content(output)?;
-    let end = output.seek(SeekFrom::Current(0))?;
+    let end = output.stream_position()?;
 
     output.seek(SeekFrom::Start(start - 4))?;
     encode_varint_4(Varint::Value(end - start), output)?;
@@ -197,6 +233,25 @@ pub fn encode_integer<T: Write>(tag: u64, value: u64, output: &mut T) -> IoResul
     output.write_all(&buffer[..])
 }
 
+/// Tries to write a simple EBML tag with a signed integer value, in as few bytes as it fits in
+pub fn encode_signed_integer<T: Write>(tag: u64, value: i64, output: &mut T) -> IoResult<()> {
+    let mut size = 1;
+    while size < 8 && (value >> (8 * size - 1)) != (value >> 63) {
+        size += 1;
+    }
+    encode_bytes(tag, &value.to_be_bytes()[8 - size..], output)
+}
+
+/// Tries to write a simple EBML tag with a (double-precision) float value
+pub fn encode_float<T: Write>(tag: u64, value: f64, output: &mut T) -> IoResult<()> {
+    encode_tag_header(tag, Varint::Value(8), output)?;
+
+    let mut buffer = [0; 8];
+    buffer.as_mut().put_f64(value);
+
+    output.write_all(&buffer[..])
+}
+
 pub struct EbmlLayout {
     pub element_id: u64,
     pub body_offset: usize,
@@ -262,6 +317,7 @@ pub trait FromEbml<'a>: Sized {
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
     use bytes::BytesMut;
     use crate::ebml::*;
@@ -294,6 +350,24 @@ mod tests {
         if let Ok(Some((Value(3), 1))) = decode_varint(&[0x83, 0x11]) {} else {assert!(false)}
     }
 
+    #[test]
+    fn encode_signed_integers() {
+        let encoded = |value| {
+            let mut buffer = Vec::new();
+            encode_signed_integer(0x7B, value, &mut buffer).unwrap();
+            buffer
+        };
+        assert_eq!(encoded(0), [0xFB, 0x81, 0x00]);
+        assert_eq!(encoded(-33), [0xFB, 0x81, 0xDF]);
+        assert_eq!(encoded(127), [0xFB, 0x81, 0x7F]);
+        assert_eq!(encoded(128), [0xFB, 0x82, 0x00, 0x80]);
+        assert_eq!(encoded(-129), [0xFB, 0x82, 0xFF, 0x7F]);
+        assert_eq!(encoded(i64::MIN), [0xFB, 0x88, 0x80, 0, 0, 0, 0, 0, 0, 0]);
+        for value in [0, 1, -1, 66, -33, 1 << 40, -(1 << 40), i64::MAX, i64::MIN] {
+            assert_eq!(decode_int(&encoded(value)[2..]).unwrap(), value);
+        }
+    }
+
     #[test]
     fn encode_varints() {
         let mut buffer = BytesMut::with_capacity(10).writer();
@@ -312,7 +386,7 @@ mod tests {
         assert_eq!(encode_varint(Varint::Unknown, &mut no_space_writer).unwrap_err().kind(), ErrorKind::WriteZero);
 
         encode_varint(Varint::Value(0), &mut buffer).unwrap();
-        assert_eq!(buffer.get_mut().split_to(1), &[0x80 | 0].as_ref());
+        assert_eq!(buffer.get_mut().split_to(1), &[0x80].as_ref());
         assert_eq!(encode_varint(Varint::Value(0), &mut no_space_writer).unwrap_err().kind(), ErrorKind::WriteZero);
 
         encode_varint(Varint::Value(1), &mut buffer).unwrap();
@@ -362,7 +436,7 @@ mod tests {
         assert_eq!(&buffer.get_mut().split_to(8), &[0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE].as_ref());
 
         assert_eq!(encode_varint(Varint::Value(0xFFFFFFFFFFFFFF), &mut buffer).unwrap_err().kind(), ErrorKind::InvalidInput);
-        assert_eq!(encode_varint(Varint::Value(u64::max_value()), &mut buffer).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(encode_varint(Varint::Value(u64::MAX), &mut buffer).unwrap_err().kind(), ErrorKind::InvalidInput);
     }
 
     #[test]
@@ -408,20 +482,43 @@ mod tests {
         assert_eq!(decode_uint(&[0x80,0,0,0,0,0,0,1]).unwrap(), 9223372036854775809);
     }
 
+    #[test]
+    fn parse_ints() {
+        assert_eq!(decode_int(&[0]).unwrap(), 0);
+        assert_eq!(decode_int(&[0x21]).unwrap(), 33);
+        assert_eq!(decode_int(&[0xDF]).unwrap(), -33);
+        assert_eq!(decode_int(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xDF]).unwrap(), -33);
+        assert_eq!(decode_int(&[0x80, 0, 0, 0, 0, 0, 0, 0]).unwrap(), i64::MIN);
+        if let Err(EbmlError::CorruptPayload) = decode_int(&[]) {} else {assert!(false)}
+        if let Err(EbmlError::CorruptPayload) = decode_int(&[0; 9]) {} else {assert!(false)}
+    }
+
+    #[test]
+    fn parse_floats() {
+        assert_eq!(decode_float(&[]).unwrap(), 0.0);
+        assert_eq!(decode_float(&[0x3F, 0xC0, 0, 0]).unwrap(), 1.5);
+        assert_eq!(decode_float(&[0x40, 0xBF, 0x40, 0, 0, 0, 0, 0]).unwrap(), 8000.0);
+        if let Err(EbmlError::CorruptPayload) = decode_float(&[0; 3]) {} else {assert!(false)}
+    }
+
+    #[test]
+    fn parse_strings() {
+        assert_eq!(decode_string(b"V_VP9").unwrap(), "V_VP9");
+        assert_eq!(decode_string(b"und\0\0").unwrap(), "und");
+        assert_eq!(decode_string(b"").unwrap(), "");
+        if let Err(EbmlError::CorruptPayload) = decode_string(&[0xFF]) {} else {assert!(false)}
+    }
+
     #[derive(Debug, PartialEq)]
     struct GenericElement(u64, usize);
 
     impl<'a> FromEbml<'a> for GenericElement {
-        fn should_unwrap(element_id: u64) -> bool {
-            match element_id {
-                _ => false
-            }
+        fn should_unwrap(_element_id: u64) -> bool {
+            false
         }
 
         fn decode(element_id: u64, bytes: &'a[u8]) -> Result<GenericElement, EbmlError> {
-            match element_id {
-                _ => Ok(GenericElement(element_id, bytes.len()))
-            }
+            Ok(GenericElement(element_id, bytes.len()))
         }
     }
 
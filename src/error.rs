@@ -2,8 +2,11 @@
 use custom_error::custom_error;
 
 custom_error!{pub WebmetroError
-    ResourcesExceeded = "resources exceeded",
+    ResourcesExceeded{buffer: &'static str, size: usize, limit: usize} = @{
+        format!("{} buffer limit of {} bytes exceeded by {} bytes", buffer, limit, size.saturating_sub(*limit))
+    },
     EbmlError{source: crate::ebml::EbmlError} = "EBML error: {source}",
+    ChannelError{source: crate::channel::ChannelError} = "Channel error: {source}",
     HttpError{source: http::Error} = "HTTP error: {source}",
     HyperError{source: hyper::Error} = "Hyper error: {source}",
     IoError{source: std::io::Error} = "IO error: {source}",
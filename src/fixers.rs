@@ -7,10 +7,14 @@ use tokio::time::{sleep_until, Duration, Instant, Sleep};
 
 use crate::chunk::Chunk;
 
+/// Rewrites cluster timecodes so they never go backwards; all bookkeeping
+/// is in nanoseconds, so it's independent of each stream's timecode scale.
 pub struct ChunkTimecodeFixer {
-    current_offset: u64,
-    last_observed_timecode: u64,
-    assumed_duration: u64,
+    current_offset: i128,
+    last_observed_timecode: u128,
+    assumed_duration: u128,
+    /// set when the next cluster should be spliced right after the last one, even if it isn't earlier
+    rebase_next: bool,
 }
 
 impl ChunkTimecodeFixer {
@@ -18,24 +22,83 @@ impl ChunkTimecodeFixer {
         ChunkTimecodeFixer {
             current_offset: 0,
             last_observed_timecode: 0,
-            assumed_duration: 33,
+            assumed_duration: 33_000_000,
+            rebase_next: false,
         }
     }
+
+    /// The following clusters come from a different source; splice the next one
+    /// directly after the last cluster seen, whether its timecode is earlier or later
+    pub fn discontinuity(&mut self) {
+        self.rebase_next = self.last_observed_timecode > 0;
+    }
+
     pub fn process(&mut self, mut chunk: Chunk) -> Chunk {
+        if let Chunk::Cluster(ref mut cluster_head, _) = chunk {
+            let start = cluster_head.start_nanos();
+            if start < self.last_observed_timecode || self.rebase_next {
+                let next_timecode = self.last_observed_timecode + self.assumed_duration;
+                self.current_offset = next_timecode as i128 - start as i128;
+                self.rebase_next = false;
+            }
+
+            let scale = cluster_head.timecode_scale as u128;
+            let fixed_start = (start as i128 + self.current_offset) as u128;
+            cluster_head.update_timecode((fixed_start / scale) as u64);
+            self.last_observed_timecode = cluster_head.end_nanos();
+        }
+        chunk
+    }
+}
+
+impl Default for ChunkTimecodeFixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Joins the sources in a stream of chunks so it can be played straight through: after
+/// each header, clusters are dropped until the next keyframe, and timecodes never go backwards.
+pub struct SourceSplicer {
+    timecode_fixer: ChunkTimecodeFixer,
+    /// set when a source starts, until its first keyframe
+    awaiting_keyframe: bool,
+}
+
+impl SourceSplicer {
+    pub fn new() -> SourceSplicer {
+        SourceSplicer {
+            timecode_fixer: ChunkTimecodeFixer::new(),
+            awaiting_keyframe: true,
+        }
+    }
+
+    /// A different source follows, whether or not it starts with a header; its first
+    /// keyframe is spliced directly after the last cluster seen
+    pub fn discontinuity(&mut self) {
+        self.awaiting_keyframe = true;
+        self.timecode_fixer.discontinuity();
+    }
+
+    /// The chunk to pass on, with its timecode fixed, or None to drop it
+    pub fn process(&mut self, chunk: Chunk) -> Option<Chunk> {
         match chunk {
-            Chunk::Cluster(ref mut cluster_head, _) => {
-                let start = cluster_head.start;
-                if start < self.last_observed_timecode {
-                    let next_timecode = self.last_observed_timecode + self.assumed_duration;
-                    self.current_offset = next_timecode - start;
+            Chunk::Headers { .. } => self.awaiting_keyframe = true,
+            Chunk::Cluster(ref head, _) if self.awaiting_keyframe => {
+                if !head.keyframe {
+                    return None;
                 }
-
-                cluster_head.update_timecode(start + self.current_offset);
-                self.last_observed_timecode = cluster_head.end;
+                self.awaiting_keyframe = false;
             }
             _ => {}
         }
-        chunk
+        Some(self.timecode_fixer.process(chunk))
+    }
+}
+
+impl Default for SourceSplicer {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -117,7 +180,7 @@ impl<S: TryStream<Ok = Chunk> + Unpin> Stream for Throttle<S> {
 
         let next_chunk = this.stream.try_poll_next_unpin(cx);
         if let Poll::Ready(Some(Ok(Chunk::Cluster(ref cluster_head, _)))) = next_chunk {
-            let offset = Duration::from_millis(cluster_head.end);
+            let offset = Duration::from_nanos(cluster_head.end_nanos() as u64);
             // we have actual data, so start the clock if we haven't yet;
             // if we're starting the clock now, though, don't insert delays if the first chunk happens to start after zero
             let start_time = this
@@ -157,3 +220,79 @@ where
 }
 
 impl<T: TryStream<Ok = Chunk>> ChunkStream for T {}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::chunk::{Chunk, ClusterHead};
+    use crate::fixers::*;
+
+    fn cluster(start: u64, timecode_scale: u64) -> Chunk {
+        let mut head = ClusterHead::new(start).with_timecode_scale(timecode_scale);
+        head.observe_simpleblock_timecode(10);
+        Chunk::Cluster(head, Bytes::new())
+    }
+
+    fn start_nanos(chunk: &Chunk) -> u128 {
+        match chunk {
+            Chunk::Cluster(head, _) => head.start_nanos(),
+            _ => panic!("Expected a cluster")
+        }
+    }
+
+    #[test]
+    fn timecodes_stay_monotonic() {
+        let mut fixer = ChunkTimecodeFixer::new();
+        assert_eq!(start_nanos(&fixer.process(cluster(0, 1_000_000))), 0);
+        assert_eq!(start_nanos(&fixer.process(cluster(1000, 1_000_000))), 1_000_000_000);
+
+        // a restarted stream with a finer timecode scale continues after the last one
+        let restarted = fixer.process(cluster(0, 1_000));
+        assert_eq!(start_nanos(&restarted), 1_010_000_000 + 33_000_000);
+        if let Chunk::Cluster(head, _) = restarted {
+            assert_eq!(head.start, 1_043_000);
+        }
+        assert!(start_nanos(&fixer.process(cluster(1_000_000, 1_000))) > 1_043_000_000);
+    }
+
+    fn keyframe(start: u64) -> Chunk {
+        let mut chunk = cluster(start, 1_000_000);
+        if let Chunk::Cluster(ref mut head, _) = chunk {
+            head.keyframe = true;
+        }
+        chunk
+    }
+
+    #[test]
+    fn sources_spliced_at_keyframes() {
+        let header = || Chunk::Headers { bytes: Bytes::from_static(b"header") };
+        let mut splicer = SourceSplicer::new();
+        assert!(splicer.process(header()).is_some());
+        assert!(splicer.process(cluster(0, 1_000_000)).is_none());
+        assert_eq!(start_nanos(&splicer.process(keyframe(1000)).unwrap()), 1_000_000_000);
+        assert!(splicer.process(cluster(2000, 1_000_000)).is_some());
+
+        // a restarted stream waits for a keyframe too
+        assert!(splicer.process(header()).is_some());
+        assert!(splicer.process(cluster(0, 1_000_000)).is_none());
+        assert_eq!(start_nanos(&splicer.process(keyframe(500)).unwrap()), 2_010_000_000 + 33_000_000);
+
+        // as does a different source without a header of its own
+        splicer.discontinuity();
+        assert!(splicer.process(cluster(60_000, 1_000_000)).is_none());
+        assert_eq!(start_nanos(&splicer.process(keyframe(61_000)).unwrap()), 2_053_000_000 + 33_000_000);
+    }
+
+    #[test]
+    fn discontinuity_splices_later_source() {
+        let mut fixer = ChunkTimecodeFixer::new();
+        fixer.discontinuity();
+        assert_eq!(start_nanos(&fixer.process(cluster(500, 1_000_000))), 500_000_000);
+
+        // a source running ahead is pulled back to continue from the last cluster
+        fixer.discontinuity();
+        assert_eq!(start_nanos(&fixer.process(cluster(60_000, 1_000_000))), 510_000_000 + 33_000_000);
+        assert_eq!(start_nanos(&fixer.process(cluster(61_000, 1_000_000))), 1_543_000_000);
+    }
+}
@@ -0,0 +1,171 @@
+use std::io::{Result as IoResult, Write};
+
+use crate::ebml::*;
+use crate::iterator::decode_all;
+
+const TIMECODE_SCALE_ID: u64 = 0x0AD7B1;
+const DURATION_ID: u64 = 0x0489;
+const DATE_UTC_ID: u64 = 0x0461;
+const TITLE_ID: u64 = 0x3BA9;
+const MUXING_APP_ID: u64 = 0x0D80;
+const WRITING_APP_ID: u64 = 0x1741;
+
+/// Nanoseconds per timecode tick when a stream doesn't say otherwise (i.e., 1ms ticks)
+pub const DEFAULT_TIMECODE_SCALE: u64 = 1_000_000;
+
+/// Metadata about a whole Segment, parsed from an Info element
+#[derive(Debug, PartialEq, Clone)]
+pub struct SegmentInfo {
+    /// nanoseconds per timecode tick
+    pub timecode_scale: u64,
+    /// length of the segment, in timecode ticks
+    pub duration: Option<f64>,
+    /// nanoseconds since 2001-01-01T00:00:00 UTC
+    pub date_utc: Option<i64>,
+    pub title: Option<String>,
+    pub muxing_app: String,
+    pub writing_app: String,
+}
+
+impl SegmentInfo {
+    /// A copy of this info suitable for a live stream, without the
+    /// fields describing a finished recording
+    pub fn for_live_stream(&self) -> SegmentInfo {
+        SegmentInfo {
+            duration: None,
+            date_utc: None,
+            ..self.clone()
+        }
+    }
+}
+
+impl Default for SegmentInfo {
+    fn default() -> Self {
+        SegmentInfo {
+            timecode_scale: DEFAULT_TIMECODE_SCALE,
+            duration: None,
+            date_utc: None,
+            title: None,
+            muxing_app: String::new(),
+            writing_app: String::new(),
+        }
+    }
+}
+
+enum InfoElement<'a> {
+    TimecodeScale(u64),
+    Duration(f64),
+    DateUtc(i64),
+    Title(&'a str),
+    MuxingApp(&'a str),
+    WritingApp(&'a str),
+    Other
+}
+
+impl<'a> FromEbml<'a> for InfoElement<'a> {
+    fn should_unwrap(_element_id: u64) -> bool {
+        false
+    }
+
+    fn decode(element_id: u64, bytes: &'a[u8]) -> Result<InfoElement<'a>, EbmlError> {
+        match element_id {
+            TIMECODE_SCALE_ID => decode_uint(bytes).map(InfoElement::TimecodeScale),
+            DURATION_ID => decode_float(bytes).map(InfoElement::Duration),
+            DATE_UTC_ID => decode_int(bytes).map(InfoElement::DateUtc),
+            TITLE_ID => decode_string(bytes).map(InfoElement::Title),
+            MUXING_APP_ID => decode_string(bytes).map(InfoElement::MuxingApp),
+            WRITING_APP_ID => decode_string(bytes).map(InfoElement::WritingApp),
+            _ => Ok(InfoElement::Other)
+        }
+    }
+}
+
+/// Parse the body of an Info element
+pub fn parse_info(bytes: &[u8]) -> Result<SegmentInfo, EbmlError> {
+    let mut info = SegmentInfo::default();
+    for element in decode_all(bytes)? {
+        match element {
+            InfoElement::TimecodeScale(0) => return Err(EbmlError::CorruptPayload),
+            InfoElement::TimecodeScale(scale) => info.timecode_scale = scale,
+            InfoElement::Duration(duration) => info.duration = Some(duration),
+            InfoElement::DateUtc(date) => info.date_utc = Some(date),
+            InfoElement::Title(title) => info.title = Some(title.into()),
+            InfoElement::MuxingApp(app) => info.muxing_app = app.into(),
+            InfoElement::WritingApp(app) => info.writing_app = app.into(),
+            InfoElement::Other => {}
+        }
+    }
+    Ok(info)
+}
+
+/// Writes the body of an Info element; wrap it in `WebmElement::Info` to write the element itself
+pub fn encode_info<T: Write>(info: &SegmentInfo, output: &mut T) -> IoResult<()> {
+    encode_integer(TIMECODE_SCALE_ID, info.timecode_scale, output)?;
+    if let Some(duration) = info.duration {
+        encode_float(DURATION_ID, duration, output)?;
+    }
+    if let Some(date) = info.date_utc {
+        encode_integer(DATE_UTC_ID, date as u64, output)?;
+    }
+    if let Some(ref title) = info.title {
+        encode_bytes(TITLE_ID, title.as_bytes(), output)?;
+    }
+    encode_bytes(MUXING_APP_ID, info.muxing_app.as_bytes(), output)?;
+    encode_bytes(WRITING_APP_ID, info.writing_app.as_bytes(), output)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::info::*;
+    use crate::tests::TEST_FILE;
+
+    #[test]
+    fn parse_test1_info() {
+        assert_eq!(parse_info(&TEST_FILE[296..346]).unwrap(), SegmentInfo {
+            timecode_scale: 1_000_000,
+            duration: Some(3000.0),
+            muxing_app: "Lavf57.56.100".into(),
+            writing_app: "Lavf57.56.100".into(),
+            ..SegmentInfo::default()
+        });
+    }
+
+    #[test]
+    fn parse_custom_scale() {
+        let info = parse_info(&[
+            0x2A, 0xD7, 0xB1, 0x83, 0x01, 0x86, 0xA0, // TimecodeScale = 100000
+            0x7B, 0xA9, 0x84, b'T', b'e', b's', b't', // Title
+        ]).unwrap();
+        assert_eq!(info.timecode_scale, 100_000);
+        assert_eq!(info.title, Some("Test".into()));
+        assert_eq!(info.duration, None);
+    }
+
+    #[test]
+    fn info_round_trip() {
+        let info = SegmentInfo {
+            timecode_scale: 1000,
+            duration: Some(1234.5),
+            date_utc: Some(-1),
+            title: Some("Round Trip".into()),
+            muxing_app: "webmetro".into(),
+            writing_app: "test".into(),
+        };
+        let mut body = Vec::new();
+        encode_info(&info, &mut body).unwrap();
+        assert_eq!(parse_info(&body).unwrap(), info);
+
+        let mut live_body = Vec::new();
+        encode_info(&info.for_live_stream(), &mut live_body).unwrap();
+        let live = parse_info(&live_body).unwrap();
+        assert_eq!((live.duration, live.date_utc), (None, None));
+        assert_eq!(live.timecode_scale, 1000);
+        assert_eq!(live.title, Some("Round Trip".into()));
+        assert_eq!(live.muxing_app, "webmetro");
+    }
+
+    #[test]
+    fn zero_scale_rejected() {
+        if let Err(EbmlError::CorruptPayload) = parse_info(&[0x2A, 0xD7, 0xB1, 0x81, 0x00]) {} else {panic!()}
+    }
+}
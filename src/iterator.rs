@@ -1,6 +1,6 @@
 use std::marker::PhantomData;
 
-use crate::ebml::FromEbml;
+use crate::ebml::{EbmlError, FromEbml};
 
 pub struct EbmlIterator<'a, T: FromEbml<'a>>(&'a [u8], PhantomData<fn() -> T>);
 
@@ -12,9 +12,26 @@ impl<'a, T: FromEbml<'a>> Iterator for EbmlIterator<'a, T> {
     type Item = T;
 
     fn next(&mut self) -> Option<T> {
-        T::decode_element(self.0).unwrap_or(None).and_then(|(element, element_size)| {
+        T::decode_element(self.0).unwrap_or(None).map(|(element, element_size)| {
             self.0 = &self.0[element_size..];
-            Some(element)
+            element
         })
     }
 }
+
+/// Decode every element of a buffer that should hold only complete elements,
+/// such as the body of a master element. Unlike `ebml_iter`, corrupt or
+/// truncated elements are reported as errors instead of ending iteration.
+pub fn decode_all<'a, T: FromEbml<'a>>(mut source: &'a [u8]) -> Result<Vec<T>, EbmlError> {
+    let mut elements = Vec::new();
+    while !source.is_empty() {
+        match T::decode_element(source)? {
+            Some((element, element_size)) => {
+                elements.push(element);
+                source = &source[element_size..];
+            },
+            None => return Err(EbmlError::CorruptPayload)
+        }
+    }
+    Ok(elements)
+}
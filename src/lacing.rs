@@ -0,0 +1,280 @@
+use std::io::{Error as IoError, ErrorKind, Result as IoResult, Write};
+
+use crate::ebml::*;
+use crate::webm::{encode_simple_block, SimpleBlock};
+
+const LACING_MASK: u8 = 0b0000_0110;
+const XIPH_LACING: u8 = 0b0000_0010;
+const FIXED_LACING: u8 = 0b0000_0100;
+const EBML_LACING: u8 = 0b0000_0110;
+
+/// How the frames of a (Simple)Block are packed into its payload
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Lacing {
+    None,
+    Xiph,
+    Ebml,
+    FixedSize,
+}
+
+impl Lacing {
+    fn flag_bits(self) -> u8 {
+        match self {
+            Lacing::None => 0,
+            Lacing::Xiph => XIPH_LACING,
+            Lacing::Ebml => EBML_LACING,
+            Lacing::FixedSize => FIXED_LACING,
+        }
+    }
+}
+
+impl<'b> SimpleBlock<'b> {
+    pub fn lacing(&self) -> Lacing {
+        match self.flags & LACING_MASK {
+            XIPH_LACING => Lacing::Xiph,
+            FIXED_LACING => Lacing::FixedSize,
+            EBML_LACING => Lacing::Ebml,
+            _ => Lacing::None,
+        }
+    }
+
+    /// Split the block's payload into its individual frames, according to its lacing
+    pub fn frames(&self) -> Result<Vec<&'b [u8]>, EbmlError> {
+        let lacing = self.lacing();
+        if lacing == Lacing::None {
+            return Ok(vec![self.data]);
+        }
+
+        let (&count_minus_one, mut rest) = self.data.split_first().ok_or(EbmlError::CorruptPayload)?;
+        let count = count_minus_one as usize + 1;
+
+        let mut sizes = Vec::with_capacity(count);
+        match lacing {
+            Lacing::Xiph => {
+                for _ in 1..count {
+                    let mut size = 0;
+                    loop {
+                        let (&byte, remaining) = rest.split_first().ok_or(EbmlError::CorruptPayload)?;
+                        rest = remaining;
+                        size += byte as usize;
+                        if byte != 0xFF {
+                            break;
+                        }
+                    }
+                    sizes.push(size);
+                }
+            },
+            Lacing::Ebml => {
+                let mut size = 0;
+                for index in 1..count {
+                    let (value, length) = match decode_varint(rest)? {
+                        Some((Varint::Value(value), length)) => (value, length),
+                        _ => return Err(EbmlError::CorruptPayload)
+                    };
+                    rest = &rest[length..];
+                    size = if index == 1 {
+                        value as i64
+                    } else {
+                        // subsequent sizes are stored as signed differences from the previous size
+                        size + value as i64 - signed_varint_bias(length)
+                    };
+                    if size < 0 {
+                        return Err(EbmlError::CorruptPayload);
+                    }
+                    sizes.push(size as usize);
+                }
+            },
+            Lacing::FixedSize => {
+                if rest.len() % count != 0 {
+                    return Err(EbmlError::CorruptPayload);
+                }
+                sizes.resize(count - 1, rest.len() / count);
+            },
+            Lacing::None => unreachable!()
+        }
+
+        let mut frames = Vec::with_capacity(count);
+        for size in sizes {
+            if size > rest.len() {
+                return Err(EbmlError::CorruptPayload);
+            }
+            let (frame, remaining) = rest.split_at(size);
+            frames.push(frame);
+            rest = remaining;
+        }
+        // the final frame takes whatever is left over
+        frames.push(rest);
+
+        Ok(frames)
+    }
+}
+
+/// Signed varints are stored as unsigned ones offset by half the range of their length
+fn signed_varint_bias(length: usize) -> i64 {
+    (1 << (7 * length - 1)) - 1
+}
+
+fn encode_signed_varint(value: i64, output: &mut Vec<u8>) -> IoResult<()> {
+    for length in 1..=8 {
+        let bias = signed_varint_bias(length);
+        if -bias <= value && value <= bias {
+            let number = (1u64 << (7 * length)) | (value + bias) as u64;
+            output.extend_from_slice(&number.to_be_bytes()[8 - length..]);
+            return Ok(());
+        }
+    }
+    Err(IoError::new(ErrorKind::InvalidInput, WriteError::OutOfRange))
+}
+
+fn lace_frames(lacing: Lacing, frames: &[&[u8]]) -> IoResult<Vec<u8>> {
+    let invalid = || IoError::new(ErrorKind::InvalidInput, WriteError::LacingMismatch);
+
+    let mut data = Vec::new();
+    match (lacing, frames) {
+        (Lacing::None, [frame]) => {
+            data.extend_from_slice(frame);
+            return Ok(data);
+        },
+        (Lacing::None, _) => return Err(invalid()),
+        (_, []) => return Err(invalid()),
+        _ => {}
+    }
+
+    if frames.len() > 256 {
+        return Err(invalid());
+    }
+    data.push((frames.len() - 1) as u8);
+
+    let (last, laced) = frames.split_last().unwrap();
+    match lacing {
+        Lacing::Xiph => {
+            for frame in laced {
+                data.resize(data.len() + frame.len() / 0xFF, 0xFF);
+                data.push((frame.len() % 0xFF) as u8);
+            }
+        },
+        Lacing::Ebml => {
+            let mut previous_size = 0;
+            for (index, frame) in laced.iter().enumerate() {
+                if index == 0 {
+                    encode_varint(Varint::Value(frame.len() as u64), &mut data)?;
+                } else {
+                    encode_signed_varint(frame.len() as i64 - previous_size, &mut data)?;
+                }
+                previous_size = frame.len() as i64;
+            }
+        },
+        Lacing::FixedSize => {
+            if laced.iter().any(|frame| frame.len() != last.len()) {
+                return Err(invalid());
+            }
+        },
+        Lacing::None => unreachable!()
+    }
+
+    for frame in frames {
+        data.extend_from_slice(frame);
+    }
+    Ok(data)
+}
+
+/// Writes a SimpleBlock containing the given frames, packed with the given lacing;
+/// the lacing bits of `flags` are ignored.
+pub fn encode_laced_simple_block<T: Write>(track: u64, timecode: i16, flags: u8, lacing: Lacing, frames: &[&[u8]], output: &mut T) -> IoResult<()> {
+    let data = lace_frames(lacing, frames)?;
+    encode_simple_block(SimpleBlock {
+        track,
+        timecode,
+        flags: (flags & !LACING_MASK) | lacing.flag_bits(),
+        data: &data
+    }, output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::lacing::*;
+    use crate::tests::{ENCODE_WEBM_TEST_FILE, TEST_FILE};
+    use crate::webm::{parse_webm, WebmElement};
+
+    fn blocks(file: &[u8]) -> Vec<SimpleBlock<'_>> {
+        parse_webm(file).filter_map(|element| match element {
+            WebmElement::SimpleBlock(block) => Some(block),
+            _ => None
+        }).collect()
+    }
+
+    fn round_trip(lacing: Lacing, frames: &[&[u8]]) {
+        let mut cursor = Cursor::new(Vec::new());
+        encode_laced_simple_block(1, 33, 0x80, lacing, frames, &mut cursor).unwrap();
+
+        let decoded = blocks(cursor.get_ref());
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].lacing(), lacing);
+        assert_eq!(decoded[0].flags & 0x80, 0x80);
+        assert_eq!(decoded[0].timecode, 33);
+        assert_eq!(decoded[0].frames().unwrap(), frames);
+    }
+
+    #[test]
+    fn unlaced_test_files() {
+        for file in [TEST_FILE, ENCODE_WEBM_TEST_FILE] {
+            for block in blocks(file) {
+                assert_eq!(block.lacing(), Lacing::None);
+                assert_eq!(block.frames().unwrap(), vec![block.data]);
+            }
+        }
+    }
+
+    #[test]
+    fn lacing_round_trips() {
+        let test_blocks = blocks(TEST_FILE);
+        let frames: Vec<&[u8]> = test_blocks.iter().map(|block| block.data).collect();
+
+        // real frames of varied size (including sizes over 255 and shrinking sizes)
+        for window in frames.chunks(8) {
+            round_trip(Lacing::None, &window[..1]);
+            round_trip(Lacing::Xiph, window);
+            round_trip(Lacing::Ebml, window);
+        }
+
+        // fixed-size lacing needs equally-sized frames
+        let equal: Vec<&[u8]> = frames[0].chunks(frames[0].len() / 4).take(4).collect();
+        round_trip(Lacing::FixedSize, &equal);
+        round_trip(Lacing::Xiph, &equal);
+        round_trip(Lacing::Ebml, &equal);
+
+        // edge cases
+        round_trip(Lacing::Xiph, &[&[0; 255], &[], &[1; 510], &[2]]);
+        round_trip(Lacing::Ebml, &[&[0; 9000], &[1; 10], &[2; 9000], &[3]]);
+        round_trip(Lacing::FixedSize, &[&[], &[]]);
+    }
+
+    #[test]
+    fn bad_lacing_rejected() {
+        let mut sink = Vec::new();
+        assert!(encode_laced_simple_block(1, 0, 0, Lacing::None, &[&[1], &[2]], &mut sink).is_err());
+        assert!(encode_laced_simple_block(1, 0, 0, Lacing::FixedSize, &[&[1], &[2, 3]], &mut sink).is_err());
+        assert!(encode_laced_simple_block(1, 0, 0, Lacing::Xiph, &[], &mut sink).is_err());
+        assert!(encode_laced_simple_block(1, 0, 0, Lacing::Xiph, &[&[] as &[u8]; 257], &mut sink).is_err());
+        assert!(sink.is_empty());
+    }
+
+    #[test]
+    fn corrupt_lacing() {
+        let block = |flags, data| SimpleBlock { track: 1, timecode: 0, flags, data };
+
+        // no frame count
+        assert!(block(XIPH_LACING, &[]).frames().is_err());
+        // Xiph size runs past the end of the payload
+        assert!(block(XIPH_LACING, &[1, 0xFF]).frames().is_err());
+        // frame sizes exceed the payload
+        assert!(block(XIPH_LACING, &[1, 5, 0, 0]).frames().is_err());
+        assert!(block(EBML_LACING, &[1, 0x85, 0, 0]).frames().is_err());
+        // EBML size difference goes negative
+        assert!(block(EBML_LACING, &[2, 0x81, 0x80, 0]).frames().is_err());
+        // payload doesn't divide evenly
+        assert!(block(FIXED_LACING, &[1, 0, 0, 0]).frames().is_err());
+    }
+}
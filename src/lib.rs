@@ -7,7 +7,13 @@ pub mod iterator;
 pub mod stream_parser;
 
 pub mod chunk;
+pub mod dash;
+pub mod datetime;
 pub mod fixers;
+pub mod info;
+pub mod lacing;
+pub mod recording;
+pub mod tracks;
 pub mod webm;
 
 pub mod channel;
@@ -16,6 +22,37 @@ pub use crate::ebml::{EbmlError, FromEbml};
 
 #[cfg(test)]
 mod tests {
-    pub const TEST_FILE: &'static [u8] = include_bytes!("data/test1.webm");
-    pub const ENCODE_WEBM_TEST_FILE: &'static [u8] = include_bytes!("data/encode_webm_test.webm");
+    use bytes::Bytes;
+
+    use crate::chunk::{Chunk, ClusterHead};
+
+    pub const TEST_FILE: &[u8] = include_bytes!("data/test1.webm");
+    pub const ENCODE_WEBM_TEST_FILE: &[u8] = include_bytes!("data/encode_webm_test.webm");
+
+    /// a header holding just a Tracks element, with one video track
+    pub fn tracks_header(codec: &str) -> Chunk {
+        let mut entry = vec![0xD7, 0x81, 0x01, 0x83, 0x81, 0x01, 0x86, 0x80 | codec.len() as u8];
+        entry.extend_from_slice(codec.as_bytes());
+        let mut bytes = vec![0x16, 0x54, 0xAE, 0x6B, 0x80 | (entry.len() as u8 + 2), 0xAE, 0x80 | entry.len() as u8];
+        bytes.extend(entry);
+        Chunk::Headers { bytes: bytes.into() }
+    }
+
+    /// a header like `tracks_header`'s, preceded by an Info element giving the TimecodeScale
+    pub fn scaled_header(codec: &str, timecode_scale: u32) -> Chunk {
+        let mut bytes = vec![0x15, 0x49, 0xA9, 0x66, 0x88, 0x2A, 0xD7, 0xB1, 0x84];
+        bytes.extend_from_slice(&timecode_scale.to_be_bytes());
+        if let Chunk::Headers { bytes: tracks } = tracks_header(codec) {
+            bytes.extend_from_slice(&tracks);
+        }
+        Chunk::Headers { bytes: bytes.into() }
+    }
+
+    /// one second long cluster, 100 bytes of body
+    pub fn cluster(second: u64, keyframe: bool) -> Chunk {
+        let mut head = ClusterHead::new(second * 1000);
+        head.keyframe = keyframe;
+        head.observe_simpleblock_timecode(999);
+        Chunk::Cluster(head, Bytes::from(vec![0; 100]))
+    }
 }
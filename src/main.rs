@@ -17,18 +17,21 @@ struct Args {
 enum Command {
     Dump(commands::dump::DumpArgs),
     Filter(commands::filter::FilterArgs),
-    Relay(commands::relay::RelayArgs),
+    Relay(Box<commands::relay::RelayArgs>),
     Send(commands::send::SendArgs),
 }
 
 fn main() {
-    env_logger::init();
     let args = Args::parse();
+    // the relay sets up logging itself, as its config file may choose the log filter
+    if !matches!(args.command, Command::Relay(_)) {
+        env_logger::init();
+    }
 
     match args.command {
         Command::Dump(args) => commands::dump::run(args),
         Command::Filter(args) => commands::filter::run(args),
-        Command::Relay(args) => commands::relay::run(args),
+        Command::Relay(args) => commands::relay::run(*args),
         Command::Send(args) => commands::send::run(args),
     }
     .unwrap_or_else(|err| {
@@ -0,0 +1,480 @@
+use std::fs::{create_dir_all, remove_file, File, OpenOptions};
+use std::io::{BufWriter, Error as IoError, ErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+
+use crate::chunk::{Chunk, ClusterHead};
+use crate::datetime::utc_datetime;
+use crate::ebml::{decode_tag, encode_bytes, encode_integer, encode_tag_header, encode_varint, Varint};
+use crate::fixers::SourceSplicer;
+use crate::info::{encode_info, parse_info, SegmentInfo};
+use crate::tracks::{tracks_compatible, TrackEntry, TrackType};
+use crate::webm::*;
+
+const SEEK_ID: u64 = 0x0DBB;
+const SEEK_ID_ID: u64 = 0x13AB;
+const SEEK_POSITION_ID: u64 = 0x13AC;
+const CUE_POINT_ID: u64 = 0x3B;
+const CUE_TIME_ID: u64 = 0x33;
+const CUE_TRACK_POSITIONS_ID: u64 = 0x37;
+const CUE_TRACK_ID: u64 = 0x77;
+const CUE_CLUSTER_POSITION_ID: u64 = 0x71;
+
+/// Nanoseconds from the Unix epoch to 2001-01-01, which Matroska dates count from
+const MATROSKA_EPOCH_NANOS: i128 = 978_307_200_000_000_000;
+
+/// Where a channel's sources are recorded, and when to start a new file
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordingSettings {
+    pub dir: PathBuf,
+    /// start a new file at the first keyframe after one reaches this many bytes
+    pub max_bytes: Option<u64>,
+    /// start a new file at the first keyframe after one covers this much time
+    pub max_duration: Option<Duration>,
+}
+
+/// Where a cluster was written in an unfinished recording
+struct ClusterEntry {
+    offset: u64,
+    length: u64,
+    /// in timecode ticks since the recording started
+    timecode: u64,
+    keyframe: bool,
+}
+
+/// A source's header bytes, with the tracks & TimecodeScale they give
+type Header = (Bytes, Vec<TrackEntry>, Option<u64>);
+
+/// A file still being written to; until it's finished, it's a live stream
+/// in a `.part` file that can be played but not seeked
+struct OpenRecording {
+    path: PathBuf,
+    part_path: PathBuf,
+    file: File,
+    header: Bytes,
+    tracks: Vec<TrackEntry>,
+    timecode_scale: Option<u64>,
+    started: SystemTime,
+    /// the timecode this recording's clusters are moved back by, so it starts at zero
+    first_timecode: u64,
+    /// in timecode ticks since the recording started
+    end: u64,
+    written: u64,
+    clusters: Vec<ClusterEntry>,
+}
+
+/// Keep to characters that are safe in a file name on any platform
+fn file_stem(channel: &str) -> String {
+    channel
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+/// An 8-byte EBML size, so it can be filled in after the content is written
+fn size_8(size: Option<u64>) -> [u8; 8] {
+    (1 << 56 | size.unwrap_or((1 << 56) - 1)).to_be_bytes()
+}
+
+/// A SeekHead element pointing to the given elements' positions in the Segment
+fn seek_head(entries: &[(u64, u64)]) -> IoResult<Vec<u8>> {
+    let mut seeks = Vec::new();
+    for &(id, position) in entries {
+        let mut id_bytes = Vec::new();
+        encode_varint(Varint::Value(id), &mut id_bytes)?;
+        let mut seek = Vec::new();
+        encode_bytes(SEEK_ID_ID, &id_bytes, &mut seek)?;
+        encode_integer(SEEK_POSITION_ID, position, &mut seek)?;
+        encode_bytes(SEEK_ID, &seek, &mut seeks)?;
+    }
+    let mut output = Vec::new();
+    encode_bytes(SEEK_HEAD_ID, &seeks, &mut output)?;
+    Ok(output)
+}
+
+fn encode_cue_point<T: Write>(timecode: u64, track: u64, position: u64, output: &mut T) -> IoResult<()> {
+    let mut positions = Vec::new();
+    encode_integer(CUE_TRACK_ID, track, &mut positions)?;
+    encode_integer(CUE_CLUSTER_POSITION_ID, position, &mut positions)?;
+    let mut point = Vec::new();
+    encode_integer(CUE_TIME_ID, timecode, &mut point)?;
+    encode_bytes(CUE_TRACK_POSITIONS_ID, &positions, &mut point)?;
+    encode_bytes(CUE_POINT_ID, &point, output)
+}
+
+impl OpenRecording {
+    /// Start a file named by the channel & current time, beginning with the given header
+    fn create(settings: &RecordingSettings, stem: &str, (header, tracks, timecode_scale): Header, first_timecode: u64) -> IoResult<OpenRecording> {
+        create_dir_all(&settings.dir)?;
+        let started = SystemTime::now();
+        let name = format!("{}-{}", stem, utc_datetime(started).replace(':', "-"));
+        let mut suffix = 0;
+        let (path, part_path, mut file) = loop {
+            let name = match suffix {
+                0 => name.clone(),
+                n => format!("{}-{}", name, n),
+            };
+            let path = settings.dir.join(format!("{}.webm", name));
+            let part_path = settings.dir.join(format!("{}.webm.part", name));
+            if !path.exists() {
+                match OpenOptions::new().write(true).create_new(true).open(&part_path) {
+                    Ok(file) => break (path, part_path, file),
+                    Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            suffix += 1;
+        };
+        file.write_all(&header)?;
+        info!("Recording to {}", part_path.display());
+
+        Ok(OpenRecording {
+            path,
+            part_path,
+            file,
+            written: header.len() as u64,
+            header,
+            tracks,
+            timecode_scale,
+            started,
+            first_timecode,
+            end: 0,
+            clusters: Vec::new(),
+        })
+    }
+
+    /// Whether a new file should be started, if this cluster can start one
+    fn full(&self, settings: &RecordingSettings, head: &ClusterHead) -> bool {
+        let elapsed = head.start.saturating_sub(self.first_timecode) as u128 * head.timecode_scale as u128;
+        matches!(settings.max_bytes, Some(max) if self.written >= max)
+            || matches!(settings.max_duration, Some(max) if elapsed >= max.as_nanos())
+    }
+
+    fn write_cluster(&mut self, mut head: ClusterHead, body: Bytes) -> IoResult<()> {
+        head.update_timecode(head.start.saturating_sub(self.first_timecode));
+        let (timecode, end, keyframe) = (head.start, head.end, head.keyframe);
+        let mut length = 0;
+        for bytes in Chunk::Cluster(head, body) {
+            self.file.write_all(&bytes)?;
+            length += bytes.len() as u64;
+        }
+        self.clusters.push(ClusterEntry {
+            offset: self.written,
+            length,
+            timecode,
+            keyframe,
+        });
+        self.written += length;
+        self.end = end;
+        Ok(())
+    }
+
+    /// Rewrite the recording into its final, seekable file, returning its path
+    fn finish(self) -> IoResult<PathBuf> {
+        let mut part = File::open(&self.part_path)?;
+        let mut output = BufWriter::new(File::create(&self.path)?);
+        self.write_seekable(&mut part, &mut output)?;
+        output.into_inner().map_err(|err| err.into_error())?;
+        remove_file(&self.part_path)?;
+        Ok(self.path)
+    }
+
+    /// Write the recording with sizes filled in, its duration, and Cues pointing to
+    /// its keyframes, with a SeekHead up front so players can find them
+    fn write_seekable<R: Read + Seek, W: Write + Seek>(&self, part: &mut R, output: &mut W) -> IoResult<()> {
+        let mut info = SegmentInfo::default();
+        let mut tracks: &[u8] = &[];
+        for element in parse_webm(&self.header) {
+            match element {
+                WebmElement::Info(data) => info = parse_info(data).unwrap_or_default(),
+                WebmElement::Tracks(data) => tracks = data,
+                _ => {}
+            }
+        }
+        info.duration = Some(self.end as f64);
+        let started = self.started.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as i128;
+        info.date_utc = Some((started - MATROSKA_EPOCH_NANOS) as i64);
+        let mut info_bytes = Vec::new();
+        encode_info(&info, &mut info_bytes)?;
+
+        // cue on the video track, or else whatever track comes first
+        let cue_track = self.tracks.iter()
+            .find(|track| track.track_type == TrackType::Video)
+            .or_else(|| self.tracks.first())
+            .map(|track| track.number);
+        let mut seeks = vec![(SEGMENT_INFO_ID, 0), (TRACKS_ID, 0)];
+        if cue_track.is_some() {
+            seeks.push((CUES_ID, 0));
+        }
+
+        encode_webm_element(WebmElement::EbmlHead, output)?;
+        encode_varint(Varint::Value(SEGMENT_ID), output)?;
+        let size_position = output.stream_position()?;
+        output.write_all(&size_8(None))?;
+        let segment_start = output.stream_position()?;
+        // written again once the positions are known; it stays the same size
+        output.write_all(&seek_head(&seeks)?)?;
+        seeks[0].1 = output.stream_position()? - segment_start;
+        encode_webm_element(WebmElement::Info(&info_bytes), output)?;
+        seeks[1].1 = output.stream_position()? - segment_start;
+        encode_webm_element(WebmElement::Tracks(tracks), output)?;
+
+        let mut cues = Vec::new();
+        let mut buffer = Vec::new();
+        for cluster in self.clusters.iter() {
+            let position = output.stream_position()? - segment_start;
+            buffer.resize(cluster.length as usize, 0);
+            part.seek(SeekFrom::Start(cluster.offset))?;
+            part.read_exact(&mut buffer)?;
+            // live clusters have an unknown size; give them their real one
+            let tag_length = match decode_tag(&buffer) {
+                Ok(Some((CLUSTER_ID, _, tag_length))) => tag_length,
+                _ => return Err(IoError::new(ErrorKind::InvalidData, "recording is missing a cluster")),
+            };
+            encode_tag_header(CLUSTER_ID, Varint::Value((buffer.len() - tag_length) as u64), output)?;
+            output.write_all(&buffer[tag_length..])?;
+            if let (true, Some(track)) = (cluster.keyframe, cue_track) {
+                encode_cue_point(cluster.timecode, track, position, &mut cues)?;
+            }
+        }
+        if cue_track.is_some() {
+            seeks[2].1 = output.stream_position()? - segment_start;
+            encode_bytes(CUES_ID, &cues, output)?;
+        }
+
+        let end = output.stream_position()?;
+        output.seek(SeekFrom::Start(segment_start))?;
+        output.write_all(&seek_head(&seeks)?)?;
+        output.seek(SeekFrom::Start(size_position))?;
+        output.write_all(&size_8(Some(end - segment_start)))?;
+        output.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+}
+
+/// Writes a source's chunks to files named by channel & start time, starting a new
+/// file when one gets too big or long, and making each seekable once it's finished
+pub struct Recorder {
+    stem: String,
+    settings: RecordingSettings,
+    splicer: SourceSplicer,
+    header: Option<Header>,
+    recording: Option<OpenRecording>,
+}
+
+impl Recorder {
+    pub fn new(channel: &str, settings: RecordingSettings) -> Recorder {
+        Recorder {
+            stem: file_stem(channel),
+            settings,
+            splicer: SourceSplicer::new(),
+            header: None,
+            recording: None,
+        }
+    }
+
+    /// Write the source's next chunk, as given to its `Transmitter`
+    pub fn push(&mut self, chunk: Chunk) -> IoResult<()> {
+        let chunk = match self.splicer.process(chunk) {
+            Some(chunk) => chunk,
+            None => return Ok(()),
+        };
+        match chunk {
+            Chunk::Headers { ref bytes } => {
+                let tracks = chunk.tracks().unwrap_or_default();
+                let timecode_scale = chunk.timecode_scale();
+                // a restarted stream can carry on in the same file, if it could be played through
+                let compatible = matches!(self.recording, Some(ref recording)
+                    if tracks_compatible(&recording.tracks, &tracks) && recording.timecode_scale == timecode_scale);
+                if !compatible {
+                    self.finish()?;
+                }
+                self.header = Some((bytes.clone(), tracks, timecode_scale));
+                Ok(())
+            }
+            Chunk::Cluster(head, body) => self.push_cluster(head, body),
+            _ => Ok(()),
+        }
+    }
+
+    fn push_cluster(&mut self, head: ClusterHead, body: Bytes) -> IoResult<()> {
+        if head.keyframe && matches!(self.recording, Some(ref recording) if recording.full(&self.settings, &head)) {
+            self.finish()?;
+        }
+        if self.recording.is_none() {
+            let header = match self.header {
+                Some(ref header) => header.clone(),
+                None => return Ok(()),
+            };
+            self.recording = Some(OpenRecording::create(&self.settings, &self.stem, header, head.start)?);
+        }
+        match self.recording {
+            Some(ref mut recording) => recording.write_cluster(head, body),
+            None => Ok(()),
+        }
+    }
+
+    /// Finish the file being written, if there is one, making it seekable
+    pub fn finish(&mut self) -> IoResult<()> {
+        if let Some(recording) = self.recording.take() {
+            let path = recording.finish()?;
+            info!("Recorded {}", path.display());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::fs::{read, read_dir, remove_dir_all};
+    use std::path::Path;
+
+    use futures::{prelude::*, stream::iter};
+
+    use crate::chunk::WebmStream;
+    use crate::error::WebmetroError;
+    use crate::recording::*;
+    use crate::stream_parser::StreamEbml;
+    use crate::tests::{scaled_header, tracks_header, TEST_FILE};
+
+    fn test_chunks() -> Vec<Chunk> {
+        iter(Some(Result::<Bytes, WebmetroError>::Ok(Bytes::from_static(TEST_FILE))))
+            .parse_ebml()
+            .chunk_webm()
+            .try_collect()
+            .now_or_never()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn keyframe(second: u64) -> Chunk {
+        let mut head = ClusterHead::new(second * 1000);
+        head.keyframe = true;
+        Chunk::Cluster(head, Bytes::new())
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("webmetro-{}-{}", name, std::process::id()));
+        remove_dir_all(&dir).ok();
+        dir
+    }
+
+    /// the files in a directory, sorted by name
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn finished_recording_seekable() {
+        let dir = test_dir("recording-seekable");
+        let mut recorder = Recorder::new("main/../a b", RecordingSettings {
+            dir: dir.clone(),
+            max_bytes: None,
+            max_duration: None,
+        });
+        for chunk in test_chunks() {
+            recorder.push(chunk).unwrap();
+        }
+        assert_eq!(files(&dir).len(), 1);
+        assert!(files(&dir)[0].to_string_lossy().ends_with(".webm.part"));
+        recorder.finish().unwrap();
+
+        let files = files(&dir);
+        assert_eq!(files.len(), 1);
+        let name = files[0].file_name().unwrap().to_string_lossy().into_owned();
+        assert!(name.starts_with("main____a_b-") && name.ends_with("Z.webm"), "{}", name);
+        assert!(!name.contains(':'));
+
+        let bytes = read(&files[0]).unwrap();
+        let elements: Vec<WebmElement> = parse_webm(&bytes).collect();
+        assert!(matches!(elements[..], [WebmElement::EbmlHead, WebmElement::Segment, WebmElement::SeekHead, WebmElement::Info(_), WebmElement::Tracks(_), ..]));
+        assert_eq!(elements.last(), Some(&WebmElement::Cues));
+        let clusters = elements.iter().filter(|element| **element == WebmElement::Cluster).count();
+        assert_eq!(clusters, test_chunks().iter().filter(|chunk| matches!(chunk, Chunk::Cluster(..))).count());
+
+        let info = elements.iter().find_map(|element| match element {
+            WebmElement::Info(data) => Some(parse_info(data).unwrap()),
+            _ => None
+        }).unwrap();
+        assert!(info.duration.unwrap() > 2000.0);
+        assert!(info.date_utc.unwrap() > 0);
+
+        // the Segment's size is known, & the SeekHead points to the Cues
+        let segment = bytes.windows(4).position(|id| id == [0x18, 0x53, 0x80, 0x67]).unwrap();
+        let (_, size, tag_length) = decode_tag(&bytes[segment..]).unwrap().unwrap();
+        let segment_start = segment + tag_length;
+        assert_eq!(size, Varint::Value((bytes.len() - segment_start) as u64));
+        let cues_seek = bytes.windows(7).position(|seek| seek == [0x53, 0xAB, 0x84, 0x1C, 0x53, 0xBB, 0x6B]).unwrap();
+        let cues_position = u64::from_be_bytes(bytes[cues_seek + 10..cues_seek + 18].try_into().unwrap());
+        assert_eq!(bytes[segment_start + cues_position as usize..][..4], [0x1C, 0x53, 0xBB, 0x6B]);
+
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn recordings_rotated_at_keyframes() {
+        let dir = test_dir("recording-rotated");
+        let mut recorder = Recorder::new("main", RecordingSettings {
+            dir: dir.clone(),
+            max_bytes: None,
+            max_duration: Some(Duration::from_secs(2)),
+        });
+        let header = test_chunks().remove(0);
+        recorder.push(header.clone()).unwrap();
+        for second in 0..5 {
+            recorder.push(keyframe(second)).unwrap();
+        }
+        // a source with other codecs can't share a file
+        recorder.push(Chunk::Headers { bytes: Bytes::new() }).unwrap();
+        recorder.finish().unwrap();
+
+        let files = files(&dir);
+        assert_eq!(files.len(), 3);
+        let mut durations: Vec<f64> = Vec::new();
+        for file in files.iter() {
+            let bytes = read(file).unwrap();
+            // each file starts from zero
+            let timecode = parse_webm(&bytes).find_map(|element| match element {
+                WebmElement::Timecode(timecode) => Some(timecode),
+                _ => None
+            });
+            assert_eq!(timecode, Some(0));
+            durations.extend(parse_webm(&bytes).find_map(|element| match element {
+                WebmElement::Info(data) => parse_info(data).unwrap().duration,
+                _ => None
+            }));
+        }
+        durations.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(durations, vec![0.0, 1000.0, 1000.0]);
+
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn restarted_source_continues_with_same_timecode_scale() {
+        let dir = test_dir("recording-restarted");
+        let mut recorder = Recorder::new("main", RecordingSettings {
+            dir: dir.clone(),
+            max_bytes: None,
+            max_duration: None,
+        });
+        recorder.push(tracks_header("V_VP9")).unwrap();
+        recorder.push(keyframe(0)).unwrap();
+        recorder.push(tracks_header("V_VP9")).unwrap();
+        recorder.push(keyframe(1)).unwrap();
+        assert_eq!(files(&dir).len(), 1);
+
+        // the same codecs on another scale would play at the wrong speed
+        recorder.push(scaled_header("V_VP9", 100_000)).unwrap();
+        recorder.push(keyframe(2)).unwrap();
+        recorder.finish().unwrap();
+        assert_eq!(files(&dir).len(), 2);
+
+        remove_dir_all(dir).unwrap();
+    }
+}
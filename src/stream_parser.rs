@@ -2,7 +2,7 @@ use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures::{TryStreamExt, stream::{Stream, StreamExt}};
 use std::task::{Context, Poll};
 
-use crate::ebml::FromEbml;
+use crate::ebml::{decode_tag, FromEbml, Varint};
 use crate::error::WebmetroError;
 
 pub struct EbmlStreamingParser<S> {
@@ -13,7 +13,7 @@ pub struct EbmlStreamingParser<S> {
 }
 
 impl<S> EbmlStreamingParser<S> {
-    /// add a "soft" buffer size limit; if the input buffer exceeds this size,
+    /// add a "soft" buffer size limit; if an element won't fit in this size,
     /// error the stream instead of resuming. It's still possible for the buffer
     /// to exceed this size *after* a fill, so ensure input sizes are reasonable.
     pub fn with_soft_limit(mut self, limit: usize) -> Self {
@@ -40,6 +40,26 @@ impl<I: Buf, E, S: Stream<Item = Result<I, E>> + Unpin> EbmlStreamingParser<S>
 where
     WebmetroError: From<E>,
 {
+    /// Error if the incomplete element in the buffer can't fit in the size limit;
+    /// once its header has arrived, its full size is known before it's buffered.
+    fn check_limit<'a, T: FromEbml<'a>>(&self) -> Result<(), WebmetroError> {
+        let limit = match self.buffer_size_limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let (size, exceeded) = match decode_tag(&self.buffer) {
+            Ok(Some((element_id, Varint::Value(payload_size), body_offset))) if !T::should_unwrap(element_id) => {
+                let size = body_offset.saturating_add(payload_size as usize);
+                (size, size > limit)
+            }
+            _ => (self.buffer.len(), self.buffer.len() >= limit),
+        };
+        if exceeded {
+            return Err(WebmetroError::ResourcesExceeded { buffer: "parser", size, limit });
+        }
+        Ok(())
+    }
+
     pub fn poll_event<'a, T: FromEbml<'a>>(
         &'a mut self,
         cx: &mut Context,
@@ -59,11 +79,7 @@ where
                 }
             }
 
-            if let Some(limit) = self.buffer_size_limit {
-                if limit <= self.buffer.len() {
-                    return Poll::Ready(Some(Err(WebmetroError::ResourcesExceeded)));
-                }
-            }
+            self.check_limit::<T>()?;
 
             match self.stream.poll_next_unpin(cx)? {
                 Poll::Ready(Some(buf)) => {
@@ -86,12 +102,7 @@ where
                 return Ok(Some(T::decode(info.element_id, &self.borrowed)?));
             }
 
-            if let Some(limit) = self.buffer_size_limit {
-                if limit <= self.buffer.len() {
-                    // hit our buffer limit and still nothing parsed
-                    return Err(WebmetroError::ResourcesExceeded);
-                }
-            }
+            self.check_limit::<T>()?;
 
             match self.stream.try_next().await? {
                 Some(refill) => {
@@ -120,7 +131,7 @@ mod tests {
     #[test]
     fn stream_webm_test() {
         poll_fn(|cx| {
-            let pieces = vec![
+            let pieces = [
                 &ENCODE_WEBM_TEST_FILE[0..20],
                 &ENCODE_WEBM_TEST_FILE[20..40],
                 &ENCODE_WEBM_TEST_FILE[40..],
@@ -171,7 +182,7 @@ mod tests {
 
     #[test]
     fn async_webm_test() {
-        let pieces = vec![
+        let pieces = [
             &ENCODE_WEBM_TEST_FILE[0..20],
             &ENCODE_WEBM_TEST_FILE[20..40],
             &ENCODE_WEBM_TEST_FILE[40..],
@@ -197,4 +208,21 @@ mod tests {
             .expect("Test tried to block on I/O")
             .expect("Parse failed");
     }
+
+    #[test]
+    fn oversized_element_rejected() {
+        let mut parser = futures::stream::iter(ENCODE_WEBM_TEST_FILE.chunks(5))
+            .map(Ok::<&[u8], WebmetroError>)
+            .parse_ebml()
+            .with_soft_limit(10);
+
+        // the EBML header's full size is known as soon as its tag arrives
+        let err = parser
+            .next::<WebmElement>()
+            .now_or_never()
+            .expect("Test tried to block on I/O")
+            .unwrap_err();
+        assert_matches!(err, WebmetroError::ResourcesExceeded { buffer: "parser", size: 15, limit: 10 });
+        assert_eq!(err.to_string(), "parser buffer limit of 10 bytes exceeded by 5 bytes");
+    }
 }
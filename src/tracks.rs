@@ -0,0 +1,322 @@
+use crate::ebml::*;
+use crate::iterator::decode_all;
+
+const TRACK_ENTRY_ID: u64 = 0x2E;
+const TRACK_NUMBER_ID: u64 = 0x57;
+const TRACK_UID_ID: u64 = 0x33C5;
+const TRACK_TYPE_ID: u64 = 0x03;
+const FLAG_ENABLED_ID: u64 = 0x39;
+const FLAG_DEFAULT_ID: u64 = 0x08;
+const FLAG_FORCED_ID: u64 = 0x15AA;
+const DEFAULT_DURATION_ID: u64 = 0x03E383;
+const NAME_ID: u64 = 0x136E;
+const LANGUAGE_ID: u64 = 0x02B59C;
+const CODEC_ID_ID: u64 = 0x06;
+const CODEC_PRIVATE_ID: u64 = 0x23A2;
+const VIDEO_ID: u64 = 0x60;
+const PIXEL_WIDTH_ID: u64 = 0x30;
+const PIXEL_HEIGHT_ID: u64 = 0x3A;
+const FRAME_RATE_ID: u64 = 0x0383E3;
+const AUDIO_ID: u64 = 0x61;
+const SAMPLING_FREQUENCY_ID: u64 = 0x35;
+const CHANNELS_ID: u64 = 0x1F;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum TrackType {
+    Video,
+    Audio,
+    Complex,
+    Logo,
+    Subtitle,
+    Buttons,
+    Control,
+    Metadata,
+    Other(u64)
+}
+
+impl From<u64> for TrackType {
+    fn from(value: u64) -> TrackType {
+        match value {
+            1 => TrackType::Video,
+            2 => TrackType::Audio,
+            3 => TrackType::Complex,
+            0x10 => TrackType::Logo,
+            0x11 => TrackType::Subtitle,
+            0x12 => TrackType::Buttons,
+            0x20 => TrackType::Control,
+            0x21 => TrackType::Metadata,
+            other => TrackType::Other(other)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct VideoSettings {
+    pub pixel_width: u64,
+    pub pixel_height: u64,
+    /// frames per second, if the muxer wrote the (rarely-used) FrameRate element;
+    /// see `TrackEntry::frame_rate` for a more useful estimate
+    pub frame_rate: Option<f64>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct AudioSettings {
+    pub sampling_frequency: f64,
+    pub channels: u64,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        AudioSettings {
+            sampling_frequency: 8000.0,
+            channels: 1,
+        }
+    }
+}
+
+/// Metadata describing one track of a WebM stream, parsed from a TrackEntry element.
+/// Absent fields take the defaults given by the Matroska specification.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TrackEntry {
+    pub number: u64,
+    pub uid: u64,
+    pub track_type: TrackType,
+    pub codec_id: String,
+    pub codec_private: Option<Vec<u8>>,
+    pub enabled: bool,
+    pub default: bool,
+    pub forced: bool,
+    /// nanoseconds per frame, if constant
+    pub default_duration: Option<u64>,
+    pub language: String,
+    pub name: Option<String>,
+    pub video: Option<VideoSettings>,
+    pub audio: Option<AudioSettings>,
+}
+
+impl Default for TrackEntry {
+    fn default() -> Self {
+        TrackEntry {
+            number: 0,
+            uid: 0,
+            track_type: TrackType::Other(0),
+            codec_id: String::new(),
+            codec_private: None,
+            enabled: true,
+            default: true,
+            forced: false,
+            default_duration: None,
+            language: "eng".into(),
+            name: None,
+            video: None,
+            audio: None,
+        }
+    }
+}
+
+impl TrackEntry {
+    /// Frames per second, from the FrameRate element if given, or else derived from DefaultDuration
+    pub fn frame_rate(&self) -> Option<f64> {
+        self.video.as_ref().and_then(|video| video.frame_rate).or_else(|| {
+            self.default_duration
+                .filter(|&duration| duration > 0)
+                .map(|duration| 1_000_000_000.0 / duration as f64)
+        })
+    }
+}
+
+/// Whether a player set up for one list of tracks could decode the other:
+/// the same tracks, with the same types and codecs
+pub fn tracks_compatible(a: &[TrackEntry], b: &[TrackEntry]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| {
+        a.number == b.number
+            && a.track_type == b.track_type
+            && a.codec_id == b.codec_id
+            && a.codec_private == b.codec_private
+    })
+}
+
+enum TrackElement<'a> {
+    Entry(&'a [u8]),
+    Number(u64),
+    Uid(u64),
+    Type(u64),
+    Enabled(u64),
+    Default(u64),
+    Forced(u64),
+    DefaultDuration(u64),
+    Name(&'a str),
+    Language(&'a str),
+    CodecId(&'a str),
+    CodecPrivate(&'a [u8]),
+    Video(&'a [u8]),
+    PixelWidth(u64),
+    PixelHeight(u64),
+    FrameRate(f64),
+    Audio(&'a [u8]),
+    SamplingFrequency(f64),
+    Channels(u64),
+    Other
+}
+
+impl<'a> FromEbml<'a> for TrackElement<'a> {
+    fn should_unwrap(_element_id: u64) -> bool {
+        false
+    }
+
+    fn decode(element_id: u64, bytes: &'a[u8]) -> Result<TrackElement<'a>, EbmlError> {
+        match element_id {
+            TRACK_ENTRY_ID => Ok(TrackElement::Entry(bytes)),
+            TRACK_NUMBER_ID => decode_uint(bytes).map(TrackElement::Number),
+            TRACK_UID_ID => decode_uint(bytes).map(TrackElement::Uid),
+            TRACK_TYPE_ID => decode_uint(bytes).map(TrackElement::Type),
+            FLAG_ENABLED_ID => decode_uint(bytes).map(TrackElement::Enabled),
+            FLAG_DEFAULT_ID => decode_uint(bytes).map(TrackElement::Default),
+            FLAG_FORCED_ID => decode_uint(bytes).map(TrackElement::Forced),
+            DEFAULT_DURATION_ID => decode_uint(bytes).map(TrackElement::DefaultDuration),
+            NAME_ID => decode_string(bytes).map(TrackElement::Name),
+            LANGUAGE_ID => decode_string(bytes).map(TrackElement::Language),
+            CODEC_ID_ID => decode_string(bytes).map(TrackElement::CodecId),
+            CODEC_PRIVATE_ID => Ok(TrackElement::CodecPrivate(bytes)),
+            VIDEO_ID => Ok(TrackElement::Video(bytes)),
+            PIXEL_WIDTH_ID => decode_uint(bytes).map(TrackElement::PixelWidth),
+            PIXEL_HEIGHT_ID => decode_uint(bytes).map(TrackElement::PixelHeight),
+            FRAME_RATE_ID => decode_float(bytes).map(TrackElement::FrameRate),
+            AUDIO_ID => Ok(TrackElement::Audio(bytes)),
+            SAMPLING_FREQUENCY_ID => decode_float(bytes).map(TrackElement::SamplingFrequency),
+            CHANNELS_ID => decode_uint(bytes).map(TrackElement::Channels),
+            _ => Ok(TrackElement::Other)
+        }
+    }
+}
+
+/// Parse the body of a Tracks element into its track entries
+pub fn parse_tracks(bytes: &[u8]) -> Result<Vec<TrackEntry>, EbmlError> {
+    let mut tracks = Vec::new();
+    for element in decode_all(bytes)? {
+        if let TrackElement::Entry(entry) = element {
+            tracks.push(parse_track_entry(entry)?);
+        }
+    }
+    Ok(tracks)
+}
+
+fn parse_track_entry(bytes: &[u8]) -> Result<TrackEntry, EbmlError> {
+    let mut track = TrackEntry::default();
+    for element in decode_all(bytes)? {
+        match element {
+            TrackElement::Number(number) => track.number = number,
+            TrackElement::Uid(uid) => track.uid = uid,
+            TrackElement::Type(track_type) => track.track_type = track_type.into(),
+            TrackElement::Enabled(flag) => track.enabled = flag != 0,
+            TrackElement::Default(flag) => track.default = flag != 0,
+            TrackElement::Forced(flag) => track.forced = flag != 0,
+            TrackElement::DefaultDuration(duration) => track.default_duration = Some(duration),
+            TrackElement::Name(name) => track.name = Some(name.into()),
+            TrackElement::Language(language) => track.language = language.into(),
+            TrackElement::CodecId(codec_id) => track.codec_id = codec_id.into(),
+            TrackElement::CodecPrivate(data) => track.codec_private = Some(data.into()),
+            TrackElement::Video(video) => track.video = Some(parse_video(video)?),
+            TrackElement::Audio(audio) => track.audio = Some(parse_audio(audio)?),
+            _ => {}
+        }
+    }
+    Ok(track)
+}
+
+fn parse_video(bytes: &[u8]) -> Result<VideoSettings, EbmlError> {
+    let mut video = VideoSettings::default();
+    for element in decode_all(bytes)? {
+        match element {
+            TrackElement::PixelWidth(width) => video.pixel_width = width,
+            TrackElement::PixelHeight(height) => video.pixel_height = height,
+            TrackElement::FrameRate(rate) => video.frame_rate = Some(rate),
+            _ => {}
+        }
+    }
+    Ok(video)
+}
+
+fn parse_audio(bytes: &[u8]) -> Result<AudioSettings, EbmlError> {
+    let mut audio = AudioSettings::default();
+    for element in decode_all(bytes)? {
+        match element {
+            TrackElement::SamplingFrequency(frequency) => audio.sampling_frequency = frequency,
+            TrackElement::Channels(channels) => audio.channels = channels,
+            _ => {}
+        }
+    }
+    Ok(audio)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::TEST_FILE;
+    use crate::tracks::*;
+
+    #[test]
+    fn parse_test1_tracks() {
+        let tracks = parse_tracks(&TEST_FILE[358..421]).unwrap();
+        assert_eq!(tracks, vec![TrackEntry {
+            number: 1,
+            uid: 1,
+            track_type: TrackType::Video,
+            codec_id: "V_VP9".into(),
+            default_duration: Some(33333333),
+            language: "und".into(),
+            video: Some(VideoSettings {
+                pixel_width: 320,
+                pixel_height: 240,
+                frame_rate: None,
+            }),
+            ..TrackEntry::default()
+        }]);
+        assert_eq!(tracks[0].frame_rate().map(f64::round), Some(30.0));
+    }
+
+    #[test]
+    fn parse_audio_track() {
+        let tracks = parse_tracks(&[
+            0xAE, 0x9D,
+                0xD7, 0x81, 0x02, // TrackNumber
+                0x83, 0x81, 0x02, // TrackType
+                0x86, 0x86, b'A', b'_', b'O', b'P', b'U', b'S', // CodecID
+                0x55, 0xAA, 0x81, 0x01, // FlagForced
+                0xE1, 0x89,
+                    0xB5, 0x84, 0x47, 0x3B, 0x80, 0x00, // SamplingFrequency
+                    0x9F, 0x81, 0x02, // Channels
+        ]).unwrap();
+        assert_eq!(tracks, vec![TrackEntry {
+            number: 2,
+            track_type: TrackType::Audio,
+            codec_id: "A_OPUS".into(),
+            forced: true,
+            audio: Some(AudioSettings {
+                sampling_frequency: 48000.0,
+                channels: 2,
+            }),
+            ..TrackEntry::default()
+        }]);
+    }
+
+    #[test]
+    fn compatible_tracks() {
+        let tracks = parse_tracks(&TEST_FILE[358..421]).unwrap();
+        let mut resized = tracks.clone();
+        resized[0].video = Some(VideoSettings::default());
+        assert!(tracks_compatible(&tracks, &resized));
+
+        let mut recoded = tracks.clone();
+        recoded[0].codec_id = "V_VP8".into();
+        assert!(!tracks_compatible(&tracks, &recoded));
+        assert!(!tracks_compatible(&tracks, &[]));
+    }
+
+    #[test]
+    fn corrupt_tracks() {
+        // TrackEntry claims more content than it has
+        if let Err(EbmlError::CorruptPayload) = parse_tracks(&[0xAE, 0x84, 0xD7, 0x81, 0x01]) {} else {panic!()}
+        // child element is truncated
+        if let Err(EbmlError::CorruptPayload) = parse_tracks(&[0xAE, 0x83, 0xD7, 0x82, 0x01]) {} else {panic!()}
+    }
+}
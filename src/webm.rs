@@ -2,22 +2,30 @@ use std::io::{Error as IoError, ErrorKind, Result as IoResult, Write, Seek};
 use byteorder::{BigEndian, ByteOrder};
 use bytes::BufMut;
 use crate::ebml::*;
+use crate::iterator::decode_all;
 use crate::iterator::ebml_iter;
 use crate::iterator::EbmlIterator;
 
-const SEGMENT_ID: u64 = 0x08538067;
-const SEEK_HEAD_ID: u64 = 0x014D9B74;
-const SEGMENT_INFO_ID: u64 = 0x0549A966;
-const CUES_ID: u64 = 0x0C53BB6B;
-const TRACKS_ID: u64 = 0x0654AE6B;
-const CLUSTER_ID: u64 = 0x0F43B675;
+pub const SEGMENT_ID: u64 = 0x08538067;
+pub const SEEK_HEAD_ID: u64 = 0x014D9B74;
+pub const SEGMENT_INFO_ID: u64 = 0x0549A966;
+pub const CUES_ID: u64 = 0x0C53BB6B;
+pub const TRACKS_ID: u64 = 0x0654AE6B;
+pub const CLUSTER_ID: u64 = 0x0F43B675;
 const TIMECODE_ID: u64 = 0x67;
 const SIMPLE_BLOCK_ID: u64 = 0x23;
+const BLOCK_GROUP_ID: u64 = 0x20;
+const BLOCK_ID: u64 = 0x21;
+const BLOCK_DURATION_ID: u64 = 0x1B;
+const REFERENCE_BLOCK_ID: u64 = 0x7B;
+const DISCARD_PADDING_ID: u64 = 0x35A2;
 
-pub fn parse_webm<'a, T: AsRef<[u8]> + ?Sized>(source: &'a T) -> EbmlIterator<'a, WebmElement> {
+pub fn parse_webm<'a, T: AsRef<[u8]> + ?Sized>(source: &'a T) -> EbmlIterator<'a, WebmElement<'a>> {
     ebml_iter(source.as_ref())
 }
 
+/// The contents of a SimpleBlock, or of a Block (which shares its layout,
+/// but has no keyframe flag)
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub struct SimpleBlock<'b> {
     pub track: u64,
@@ -26,18 +34,41 @@ pub struct SimpleBlock<'b> {
     pub data: &'b[u8]
 }
 
+/// The parsed contents of a BlockGroup element
+#[derive(Debug, PartialEq, Clone)]
+pub struct BlockGroup<'b> {
+    pub block: SimpleBlock<'b>,
+    pub duration: Option<u64>,
+    /// relative timecodes of the blocks this one depends on
+    pub references: Vec<i64>,
+    /// nanoseconds of audio to discard from the decoded block
+    pub discard_padding: Option<i64>,
+}
+
+impl<'b> BlockGroup<'b> {
+    /// A block that doesn't reference any others is a keyframe
+    pub fn is_keyframe(&self) -> bool {
+        self.references.is_empty()
+    }
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum WebmElement<'b> {
     EbmlHead,
     Void,
     Segment,
     SeekHead,
-    Info,
+    Info(&'b[u8]),
     Cues,
     Tracks(&'b[u8]),
     Cluster,
     Timecode(u64),
     SimpleBlock(SimpleBlock<'b>),
+    BlockGroup(&'b[u8]),
+    Block(SimpleBlock<'b>),
+    BlockDuration(u64),
+    ReferenceBlock(i64),
+    DiscardPadding(i64),
     Unknown(u64)
 }
 
@@ -57,18 +88,23 @@ impl<'b> FromEbml<'b> for WebmElement<'b> {
             VOID_ID => Ok(WebmElement::Void),
             SEGMENT_ID => Ok(WebmElement::Segment),
             SEEK_HEAD_ID => Ok(WebmElement::SeekHead),
-            SEGMENT_INFO_ID => Ok(WebmElement::Info),
+            SEGMENT_INFO_ID => Ok(WebmElement::Info(bytes)),
             CUES_ID => Ok(WebmElement::Cues),
             TRACKS_ID => Ok(WebmElement::Tracks(bytes)),
             CLUSTER_ID => Ok(WebmElement::Cluster),
             TIMECODE_ID => decode_uint(bytes).map(WebmElement::Timecode),
-            SIMPLE_BLOCK_ID => decode_simple_block(bytes),
+            SIMPLE_BLOCK_ID => decode_block(bytes).map(WebmElement::SimpleBlock),
+            BLOCK_GROUP_ID => Ok(WebmElement::BlockGroup(bytes)),
+            BLOCK_ID => decode_block(bytes).map(WebmElement::Block),
+            BLOCK_DURATION_ID => decode_uint(bytes).map(WebmElement::BlockDuration),
+            REFERENCE_BLOCK_ID => decode_int(bytes).map(WebmElement::ReferenceBlock),
+            DISCARD_PADDING_ID => decode_int(bytes).map(WebmElement::DiscardPadding),
             _ => Ok(WebmElement::Unknown(element_id))
         }
     }
 }
 
-fn decode_simple_block(bytes: &[u8]) -> Result<WebmElement, EbmlError> {
+fn decode_block(bytes: &[u8]) -> Result<SimpleBlock<'_>, EbmlError> {
     if let Ok(Some((Varint::Value(track), track_field_len))) = decode_varint(bytes) {
         let header_len = track_field_len + 2 + 1;
         if bytes.len() < header_len {
@@ -76,18 +112,64 @@ fn decode_simple_block(bytes: &[u8]) -> Result<WebmElement, EbmlError> {
         }
         let timecode = BigEndian::read_i16(&bytes[track_field_len..]);
         let flags = bytes[track_field_len + 2];
-        return Ok(WebmElement::SimpleBlock(SimpleBlock {
-            track: track,
-            timecode: timecode,
-            flags: flags,
+        Ok(SimpleBlock {
+            track,
+            timecode,
+            flags,
             data: &bytes[header_len..],
-        }))
+        })
     } else {
-        return Err(EbmlError::CorruptPayload);
+        Err(EbmlError::CorruptPayload)
     }
 }
 
+/// Parse the body of a BlockGroup element
+pub fn parse_block_group(bytes: &[u8]) -> Result<BlockGroup<'_>, EbmlError> {
+    let mut block = None;
+    let mut duration = None;
+    let mut references = Vec::new();
+    let mut discard_padding = None;
+
+    for element in decode_all(bytes)? {
+        match element {
+            WebmElement::Block(contents) => block = Some(contents),
+            WebmElement::BlockDuration(value) => duration = Some(value),
+            WebmElement::ReferenceBlock(value) => references.push(value),
+            WebmElement::DiscardPadding(value) => discard_padding = Some(value),
+            _ => {}
+        }
+    }
+
+    Ok(BlockGroup {
+        block: block.ok_or(EbmlError::CorruptPayload)?,
+        duration,
+        references,
+        discard_padding,
+    })
+}
+
 pub fn encode_simple_block<T: Write>(block: SimpleBlock, output: &mut T) -> IoResult<()> {
+    encode_block(SIMPLE_BLOCK_ID, block, output)
+}
+
+/// Writes a complete BlockGroup element
+pub fn encode_block_group<T: Write>(group: &BlockGroup, output: &mut T) -> IoResult<()> {
+    let mut body = Vec::new();
+    encode_block(BLOCK_ID, group.block, &mut body)?;
+    if let Some(duration) = group.duration {
+        encode_integer(BLOCK_DURATION_ID, duration, &mut body)?;
+    }
+    for &reference in group.references.iter() {
+        encode_signed_integer(REFERENCE_BLOCK_ID, reference, &mut body)?;
+    }
+    if let Some(padding) = group.discard_padding {
+        encode_signed_integer(DISCARD_PADDING_ID, padding, &mut body)?;
+    }
+
+    encode_bytes(BLOCK_GROUP_ID, &body, output)
+}
+
+fn encode_block<T: Write>(tag: u64, block: SimpleBlock, output: &mut T) -> IoResult<()> {
     let SimpleBlock {
         track,
         timecode,
@@ -95,21 +177,15 @@ pub fn encode_simple_block<T: Write>(block: SimpleBlock, output: &mut T) -> IoRe
         data
     } = block;
 
-    // limiting number of tracks for now
-    if track > 31 {
-        return Err(IoError::new(ErrorKind::InvalidInput, WriteError::OutOfRange));
-    }
-    let header_len = 1 + 2 + 1;
-    encode_tag_header(SIMPLE_BLOCK_ID, Varint::Value((header_len + data.len()) as u64), output)?;
-
-    encode_varint(Varint::Value(track), output)?;
-
-    let mut buffer = [0; 3];
-    let mut cursor = buffer.as_mut();
-    cursor.put_i16(timecode);
-    cursor.put_u8(flags);
+    // the track number varint can be up to 8 bytes, followed by timecode & flags;
+    // building it first also rejects out-of-range track numbers before anything is written
+    let mut header = Vec::with_capacity(8 + 2 + 1);
+    encode_varint(Varint::Value(track), &mut header)?;
+    header.put_i16(timecode);
+    header.put_u8(flags);
 
-    output.write_all(&buffer)?;
+    encode_tag_header(tag, Varint::Value((header.len() + data.len()) as u64), output)?;
+    output.write_all(&header)?;
     output.write_all(data)
 }
 
@@ -121,12 +197,17 @@ pub fn encode_webm_element<T: Write + Seek>(element: WebmElement, output: &mut T
         WebmElement::Segment => encode_tag_header(SEGMENT_ID, Varint::Unknown, output),
         WebmElement::SeekHead => Ok(()),
         WebmElement::Cues => Ok(()),
+        WebmElement::Info(data) => encode_bytes(SEGMENT_INFO_ID, data, output),
         WebmElement::Tracks(data) => encode_bytes(TRACKS_ID, data, output),
         WebmElement::Cluster => encode_tag_header(CLUSTER_ID, Varint::Unknown, output),
         WebmElement::Timecode(time) => encode_integer(TIMECODE_ID, time, output),
         WebmElement::SimpleBlock(block) => encode_simple_block(block, output),
+        WebmElement::BlockGroup(data) => encode_bytes(BLOCK_GROUP_ID, data, output),
+        WebmElement::Block(block) => encode_block(BLOCK_ID, block, output),
+        WebmElement::BlockDuration(duration) => encode_integer(BLOCK_DURATION_ID, duration, output),
+        WebmElement::ReferenceBlock(reference) => encode_signed_integer(REFERENCE_BLOCK_ID, reference, output),
+        WebmElement::DiscardPadding(padding) => encode_signed_integer(DISCARD_PADDING_ID, padding, output),
         WebmElement::Void => Err(IoError::new(ErrorKind::InvalidInput, WriteError::OutOfRange)),
-        WebmElement::Info => Err(IoError::new(ErrorKind::InvalidInput, WriteError::OutOfRange)),
         WebmElement::Unknown(_) => Err(IoError::new(ErrorKind::InvalidInput, WriteError::OutOfRange))
     }
 }
@@ -149,7 +230,7 @@ mod tests {
         assert_eq!(iter.next(), Some(WebmElement::Segment));
         assert_eq!(iter.next(), Some(WebmElement::SeekHead));
         assert_eq!(iter.next(), Some(WebmElement::Void));
-        assert_eq!(iter.next(), Some(WebmElement::Info));
+        assert_eq!(iter.next(), Some(WebmElement::Info(&TEST_FILE[296..346])));
         assert_eq!(iter.next(), Some(WebmElement::Tracks(&TEST_FILE[358..421])));
 
         assert_eq!(iter.next(), Some(WebmElement::Cluster));
@@ -220,4 +301,86 @@ mod tests {
         assert_eq!(cursor.get_ref(), &ENCODE_WEBM_TEST_FILE);
     }
 
+    #[test]
+    fn encode_large_track_numbers() {
+        for (track, track_field_len) in [(1, 1), (31, 1), (32, 1), (126, 1), (127, 2), (300, 2), (70000, 3), (0xFFFFFFFFFFFFFE, 8)] {
+            let block = SimpleBlock {
+                track,
+                timecode: -7,
+                flags: 0x80,
+                data: "Hello, World".as_bytes()
+            };
+            let mut cursor = Cursor::new(Vec::new());
+            encode_simple_block(block, &mut cursor).unwrap();
+
+            // tag ID, size, track varint, timecode, flags, data
+            assert_eq!(cursor.get_ref().len(), 1 + 1 + track_field_len + 2 + 1 + 12);
+            let mut iter = parse_webm(cursor.get_ref());
+            assert_eq!(iter.next(), Some(WebmElement::SimpleBlock(block)));
+            assert_eq!(iter.next(), None);
+        }
+
+        let mut cursor = Cursor::new(Vec::new());
+        let error = encode_simple_block(SimpleBlock {
+            track: 0xFFFFFFFFFFFFFF,
+            timecode: 0,
+            flags: 0,
+            data: &[]
+        }, &mut cursor).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(cursor.get_ref().is_empty());
+    }
+
+    #[test]
+    fn block_group_round_trip() {
+        let group = BlockGroup {
+            block: SimpleBlock {
+                track: 2,
+                timecode: -5,
+                flags: 0x0,
+                data: "Hello, World".as_bytes()
+            },
+            duration: Some(20),
+            references: vec![-33, 66],
+            discard_padding: Some(-1),
+        };
+
+        let mut cursor = Cursor::new(Vec::new());
+        encode_block_group(&group, &mut cursor).unwrap();
+
+        let mut iter = parse_webm(cursor.get_ref());
+        let body = match iter.next() {
+            Some(WebmElement::BlockGroup(body)) => body,
+            other => panic!("Expected BlockGroup, got {:?}", other)
+        };
+        assert_eq!(iter.next(), None);
+
+        // the signed integers take a single byte each
+        assert_eq!(body[body.len() - 10..], [0xFB, 0x81, 0xDF, 0xFB, 0x81, 0x42, 0x75, 0xA2, 0x81, 0xFF]);
+
+        let mut children = parse_webm(body);
+        assert_eq!(children.next(), Some(WebmElement::Block(group.block)));
+        assert_eq!(children.next(), Some(WebmElement::BlockDuration(20)));
+        assert_eq!(children.next(), Some(WebmElement::ReferenceBlock(-33)));
+        assert_eq!(children.next(), Some(WebmElement::ReferenceBlock(66)));
+        assert_eq!(children.next(), Some(WebmElement::DiscardPadding(-1)));
+        assert_eq!(children.next(), None);
+
+        let parsed = parse_block_group(body).unwrap();
+        assert_eq!(parsed, group);
+        assert!(!parsed.is_keyframe());
+
+        // re-encoding the raw element should reproduce the same bytes
+        let mut reencoded = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::BlockGroup(body), &mut reencoded).unwrap();
+        assert_eq!(reencoded.get_ref(), cursor.get_ref());
+    }
+
+    #[test]
+    fn block_group_requires_block() {
+        let mut body = Vec::new();
+        encode_webm_element(WebmElement::BlockDuration(20), &mut Cursor::new(&mut body)).unwrap();
+        if let Err(EbmlError::CorruptPayload) = parse_block_group(&body) {} else {panic!()}
+    }
+
 }